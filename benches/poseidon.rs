@@ -0,0 +1,65 @@
+use cirpos::{hash, hash_batch, permute, Fr, Poseidon, PoseidonParams};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// Compares hashing with the cached parameters against parsing circomlib's constants for
+// every hash, as before the cache.
+fn bench_poseidon(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poseidon");
+
+    for n_inputs in [2, 5, 16] {
+        let inputs: Vec<Fr> = (0..n_inputs).map(|i| Fr::from(i as u64 + 1)).collect();
+
+        group.bench_with_input(
+            BenchmarkId::new("cached", n_inputs),
+            &inputs,
+            |b, inputs| b.iter(|| hash(black_box(inputs)).unwrap()),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("parsed", n_inputs),
+            &inputs,
+            |b, inputs| {
+                b.iter(|| {
+                    let params = PoseidonParams::circomlib(inputs.len() + 1).unwrap();
+                    let poseidon = Poseidon::new(black_box(inputs).clone());
+                    poseidon
+                        .poseidon_ex_multi_with_params(&params, Fr::zero(), 1)
+                        .unwrap()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+// The in-place permutation, without the allocations of the Vec based API.
+fn bench_permute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("permute");
+
+    group.bench_function("3", |b| {
+        let mut state = [1, 2, 3].map(Fr::from);
+        b.iter(|| permute(black_box(&mut state)).unwrap())
+    });
+
+    group.bench_function("17", |b| {
+        let mut state = [Fr::one(); 17];
+        b.iter(|| permute(black_box(&mut state)).unwrap())
+    });
+
+    group.finish();
+}
+
+// Hashing many pairs, across threads with the `parallel` feature.
+fn bench_batch(c: &mut Criterion) {
+    let inputs: Vec<[Fr; 2]> = (0..1024u64)
+        .map(|i| [Fr::from(i), Fr::from(i + 1)])
+        .collect();
+
+    c.bench_function("hash_batch/1024", |b| {
+        b.iter(|| hash_batch(black_box(&inputs)).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_poseidon, bench_permute, bench_batch);
+criterion_main!(benches);
@@ -0,0 +1,119 @@
+use super::*;
+use error::PoseidonError;
+use params::PoseidonField;
+use templates::Poseidon;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Number of inputs hashed together by `HashStream`.
+const CHUNK_SIZE: usize = 1 << 12;
+
+/// Returns Poseidon(inputs) for every item, in input order. With the `parallel` feature the
+/// work is shared across rayon's threads.
+pub fn hash_batch<F: PoseidonField, const N: usize>(
+    inputs: &[[F; N]],
+) -> Result<Vec<F>, PoseidonError> {
+    F::params(N + 1)?;
+    Ok(hash_chunk(inputs))
+}
+
+/// Returns an iterator over Poseidon(inputs) for every item of the given iterator, in input
+/// order. Inputs are read and hashed in chunks, so the whole input never has to be in memory.
+pub fn hash_stream<F: PoseidonField, const N: usize, I: IntoIterator<Item = [F; N]>>(
+    inputs: I,
+) -> Result<HashStream<F, I::IntoIter, N>, PoseidonError> {
+    F::params(N + 1)?;
+    Ok(HashStream {
+        inputs: inputs.into_iter(),
+        outputs: Vec::new().into_iter(),
+    })
+}
+
+/// Iterator returned by `hash_stream`.
+#[derive(Clone, Debug)]
+pub struct HashStream<F, I, const N: usize> {
+    inputs: I,
+    outputs: std::vec::IntoIter<F>,
+}
+
+impl<F: PoseidonField, I: Iterator<Item = [F; N]>, const N: usize> Iterator
+    for HashStream<F, I, N>
+{
+    type Item = F;
+
+    fn next(&mut self) -> Option<F> {
+        if let Some(output) = self.outputs.next() {
+            return Some(output);
+        }
+
+        let chunk: Vec<[F; N]> = self.inputs.by_ref().take(CHUNK_SIZE).collect();
+        self.outputs = hash_chunk(&chunk).into_iter();
+        self.outputs.next()
+    }
+}
+
+/// Hashes every item with `Poseidon::poseidon_ex`, the arity being checked by the caller.
+fn hash_chunk<F: PoseidonField, const N: usize>(inputs: &[[F; N]]) -> Vec<F> {
+    let hash = |item: &[F; N]| {
+        Poseidon::new(item.to_vec())
+            .poseidon_ex(F::ZERO, 1)
+            .expect("Invalid parameters!")
+    };
+
+    #[cfg(feature = "parallel")]
+    let outputs = inputs.par_iter().map(hash).collect();
+    #[cfg(not(feature = "parallel"))]
+    let outputs = inputs.iter().map(hash).collect();
+
+    outputs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use halo2::halo2curves::{bn256::Fr, ff::Field};
+    use hasher::hash;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_hash_batch() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let inputs: Vec<[Fr; 2]> = (0..1000)
+            .map(|_| [Fr::random(&mut rng), Fr::random(&mut rng)])
+            .collect();
+        let expected: Vec<Fr> = inputs.iter().map(|item| hash(item).unwrap()).collect();
+
+        assert_eq!(hash_batch(&inputs).unwrap(), expected);
+        assert_eq!(hash_batch::<Fr, 2>(&[]).unwrap(), vec![]);
+
+        let inputs = [[Fr::one(); 5], [Fr::zero(); 5]];
+        let expected: Vec<Fr> = inputs.iter().map(|item| hash(item).unwrap()).collect();
+        assert_eq!(hash_batch(&inputs).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_hash_stream() {
+        // More than one chunk, the last one partial.
+        let inputs: Vec<[Fr; 2]> = (0..CHUNK_SIZE as u64 + 100)
+            .map(|i| [Fr::from(i), Fr::from(i + 1)])
+            .collect();
+        let expected = hash_batch(&inputs).unwrap();
+
+        let outputs: Vec<Fr> = hash_stream(inputs.iter().copied()).unwrap().collect();
+        assert_eq!(outputs, expected);
+        assert_eq!(hash_stream(Vec::<[Fr; 2]>::new()).unwrap().next(), None);
+    }
+
+    #[test]
+    fn test_hash_batch_errors() {
+        assert_eq!(
+            hash_batch::<Fr, 0>(&[[]]),
+            Err(PoseidonError::UnsupportedArity(0))
+        );
+        assert_eq!(
+            hash_stream([[Fr::one(); 17]]).unwrap_err(),
+            PoseidonError::UnsupportedArity(17)
+        );
+    }
+}
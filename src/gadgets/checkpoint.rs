@@ -0,0 +1,304 @@
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use incremental::ROOT_HISTORY_SIZE;
+use merkle::MerkleTree;
+use params::PoseidonField;
+
+/// Change of one leaf, with the root after it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Change<F> {
+    index: usize,
+    old: Option<F>,
+    root: F,
+}
+
+/// `MerkleTree` with named checkpoints it can roll back to, e.g. on reorgs.
+///
+/// Every change is journaled with the previous leaf and the new root, so rolling back undoes
+/// the changes since the checkpoint. The journal and the root history keep the last
+/// `ROOT_HISTORY_SIZE` roots by default, and go further back to the oldest checkpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointedMerkleTree<F = Fr> {
+    tree: MerkleTree<F>,
+    first_root: F,
+    journal: Vec<Change<F>>,
+    checkpoints: Vec<(String, usize)>,
+    history_size: usize,
+}
+
+impl<F: PoseidonField> CheckpointedMerkleTree<F> {
+    /// Creates an empty tree of the given depth whose empty leaves are `zero`.
+    pub fn new(depth: usize, zero: F) -> Result<Self, PoseidonError> {
+        Ok(Self::from(MerkleTree::new(depth, zero)?))
+    }
+
+    /// Creates an empty tree keeping at least the given number of roots.
+    pub fn with_history_size(
+        depth: usize,
+        zero: F,
+        history_size: usize,
+    ) -> Result<Self, PoseidonError> {
+        if history_size == 0 {
+            return Err(PoseidonError::InvalidParameters);
+        }
+
+        let mut tree = Self::new(depth, zero)?;
+        tree.history_size = history_size;
+        Ok(tree)
+    }
+
+    /// Returns the underlying tree, e.g. for proofs.
+    pub fn tree(&self) -> &MerkleTree<F> {
+        &self.tree
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.tree.root()
+    }
+
+    /// Returns the roots after every journaled change, from the oldest to the current one.
+    pub fn roots(&self) -> impl Iterator<Item = &F> {
+        std::iter::once(&self.first_root).chain(self.journal.iter().map(|change| &change.root))
+    }
+
+    /// Checks whether the root is in the root history.
+    pub fn is_known_root(&self, root: F) -> bool {
+        self.roots().any(|item| *item == root)
+    }
+
+    /// Returns the names of the checkpoints, from the oldest to the newest.
+    pub fn checkpoints(&self) -> impl Iterator<Item = &str> {
+        self.checkpoints.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Appends a leaf and returns its index.
+    pub fn insert(&mut self, leaf: F) -> Result<usize, PoseidonError> {
+        let index = self.tree.insert(leaf)?;
+        self.record(index, None);
+        Ok(index)
+    }
+
+    /// Replaces the leaf at the given index.
+    pub fn update(&mut self, index: usize, leaf: F) -> Result<(), PoseidonError> {
+        let old = self.tree.leaves().get(index).copied();
+        self.tree.update(index, leaf)?;
+        self.record(index, old);
+        Ok(())
+    }
+
+    /// Replaces the leaf at the given index with the zero leaf, keeping the other indices.
+    pub fn delete(&mut self, index: usize) -> Result<(), PoseidonError> {
+        self.update(index, self.tree.zeros()[0])
+    }
+
+    /// Records the current state under the name.
+    pub fn checkpoint(&mut self, name: impl Into<String>) -> Result<(), PoseidonError> {
+        let name = name.into();
+        if self.position(&name).is_some() {
+            return Err(PoseidonError::CheckpointExists(name));
+        }
+
+        self.checkpoints.push((name, self.journal.len()));
+        Ok(())
+    }
+
+    /// Undoes the changes made since the checkpoint, dropping the newer checkpoints. The
+    /// checkpoint itself is kept.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), PoseidonError> {
+        let i = self
+            .position(name)
+            .ok_or_else(|| PoseidonError::UnknownCheckpoint(name.to_string()))?;
+        let len = self.checkpoints[i].1;
+
+        for change in self.journal.drain(len..).rev() {
+            match change.old {
+                Some(old) => self.tree.update(change.index, old)?,
+                None => {
+                    self.tree.pop();
+                }
+            }
+        }
+
+        self.checkpoints.truncate(i + 1);
+        Ok(())
+    }
+
+    /// Drops the checkpoint, trimming the journal and the root history to the oldest remaining
+    /// checkpoint or to the history size.
+    pub fn release(&mut self, name: &str) -> Result<(), PoseidonError> {
+        let i = self
+            .position(name)
+            .ok_or_else(|| PoseidonError::UnknownCheckpoint(name.to_string()))?;
+        self.checkpoints.remove(i);
+        self.trim();
+
+        Ok(())
+    }
+
+    fn record(&mut self, index: usize, old: Option<F>) {
+        let root = self.tree.root();
+        self.journal.push(Change { index, old, root });
+        self.trim();
+    }
+
+    /// Drops the changes older than both the oldest checkpoint and the last roots to keep.
+    fn trim(&mut self) {
+        let excess = (self.journal.len() + 1).saturating_sub(self.history_size);
+        let oldest = self
+            .checkpoints
+            .first()
+            .map_or(excess, |(_, len)| excess.min(*len));
+
+        if let Some(change) = self.journal.drain(..oldest).last() {
+            self.first_root = change.root;
+        }
+        for (_, len) in self.checkpoints.iter_mut() {
+            *len -= oldest;
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.checkpoints.iter().position(|(item, _)| item == name)
+    }
+}
+
+impl<F: PoseidonField> From<MerkleTree<F>> for CheckpointedMerkleTree<F> {
+    fn from(tree: MerkleTree<F>) -> Self {
+        Self {
+            first_root: tree.root(),
+            tree,
+            journal: Vec::new(),
+            checkpoints: Vec::new(),
+            history_size: ROOT_HISTORY_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use templates::Poseidon;
+
+    fn poseidon(left: Fr, right: Fr) -> Fr {
+        Poseidon::new(vec![left, right])
+            .poseidon_ex(Fr::zero(), 1)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_checkpoint_rollback() {
+        let [a, b, c, d] = [1, 2, 3, 4].map(Fr::from);
+        let zero = Fr::zero();
+
+        let mut tree = CheckpointedMerkleTree::new(2, zero).unwrap();
+        tree.insert(a).unwrap();
+        tree.insert(b).unwrap();
+        tree.checkpoint("block 1").unwrap();
+
+        // Root of [a, b, 0, 0] hashed with PoseidonEx.
+        let expected = poseidon(poseidon(a, b), poseidon(zero, zero));
+        assert_eq!(tree.root(), expected);
+        let before = tree.clone();
+
+        tree.insert(c).unwrap();
+        tree.update(0, d).unwrap();
+        tree.checkpoint("block 2").unwrap();
+        assert_eq!(tree.root(), poseidon(poseidon(d, b), poseidon(c, zero)));
+
+        tree.delete(1).unwrap();
+        tree.insert(a).unwrap();
+
+        tree.rollback_to("block 2").unwrap();
+        assert_eq!(tree.root(), poseidon(poseidon(d, b), poseidon(c, zero)));
+
+        tree.rollback_to("block 1").unwrap();
+        assert_eq!(tree.root(), expected);
+        assert_eq!(tree, before);
+        assert_eq!(
+            tree.tree(),
+            &MerkleTree::with_leaves(2, zero, &[a, b]).unwrap()
+        );
+        assert_eq!(tree.checkpoints().collect::<Vec<_>>(), ["block 1"]);
+
+        // The tree keeps working after the rollback.
+        tree.insert(c).unwrap();
+        tree.insert(d).unwrap();
+        let expected = MerkleTree::with_leaves(2, zero, &[a, b, c, d]).unwrap();
+        assert_eq!(tree.root(), expected.root());
+    }
+
+    #[test]
+    fn test_checkpoint_history() {
+        let mut tree = CheckpointedMerkleTree::with_history_size(3, Fr::zero(), 2).unwrap();
+        let mut roots = vec![tree.root()];
+        for i in 0..3 {
+            tree.insert(Fr::from(i)).unwrap();
+            roots.push(tree.root());
+        }
+
+        // Without checkpoints only the last roots are kept.
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[2..]);
+        assert_eq!(tree.journal.len(), 1);
+
+        tree.checkpoint("a").unwrap();
+        tree.insert(Fr::from(3)).unwrap();
+        roots.push(tree.root());
+        tree.checkpoint("b").unwrap();
+        tree.insert(Fr::from(4)).unwrap();
+        roots.push(tree.root());
+
+        // Checkpoints keep the roots back to the oldest one.
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[3..]);
+
+        // Releasing the oldest checkpoint trims the history to the next one.
+        tree.release("a").unwrap();
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[4..]);
+        assert!(!tree.is_known_root(roots[3]));
+
+        tree.rollback_to("b").unwrap();
+        assert_eq!(tree.root(), roots[4]);
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[4..5]);
+
+        tree.release("b").unwrap();
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[4..5]);
+
+        for i in 5..8 {
+            tree.insert(Fr::from(i)).unwrap();
+            roots.push(tree.root());
+        }
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[7..]);
+        assert_eq!(tree.journal.len(), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_errors() {
+        let mut tree = CheckpointedMerkleTree::new(1, Fr::zero()).unwrap();
+        tree.checkpoint("a").unwrap();
+
+        assert_eq!(
+            tree.checkpoint("a"),
+            Err(PoseidonError::CheckpointExists("a".to_string()))
+        );
+        assert_eq!(
+            tree.rollback_to("b"),
+            Err(PoseidonError::UnknownCheckpoint("b".to_string()))
+        );
+        assert_eq!(
+            tree.update(0, Fr::one()),
+            Err(PoseidonError::IndexOutOfRange { index: 0, len: 0 })
+        );
+        assert_eq!(
+            CheckpointedMerkleTree::with_history_size(1, Fr::zero(), 0),
+            Err(PoseidonError::InvalidParameters)
+        );
+
+        // Failed changes are not journaled.
+        tree.insert(Fr::one()).unwrap();
+        tree.insert(Fr::one()).unwrap();
+        assert_eq!(tree.insert(Fr::one()), Err(PoseidonError::TreeFull));
+        tree.rollback_to("a").unwrap();
+        assert_eq!(tree.tree(), &MerkleTree::new(1, Fr::zero()).unwrap());
+    }
+}
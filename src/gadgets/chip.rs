@@ -0,0 +1,428 @@
+use std::marker::PhantomData;
+
+use super::*;
+use halo2::{
+    circuit::{AssignedCell, Layouter, Value},
+    halo2curves::bn256::Fr,
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, Selector},
+    poly::Rotation,
+};
+use params::PoseidonField;
+
+/// Columns and selectors of the Poseidon chip for a fixed width t.
+#[derive(Clone, Debug)]
+pub struct PoseidonConfig {
+    state: Vec<Column<Advice>>,
+    c: Vec<Column<Fixed>>,
+    s: Vec<Column<Fixed>>,
+    ark: Selector,
+    full: Selector,
+    full_p: Selector,
+    partial: Selector,
+    t: usize,
+}
+
+/// Lays out circomlib's PoseidonEx in-circuit, one row per Ark / Mix / MixS / MixLast component.
+#[derive(Clone, Debug)]
+pub struct PoseidonChip<F = Fr> {
+    config: PoseidonConfig,
+    _marker: PhantomData<F>,
+}
+
+impl<F: PoseidonField> PoseidonChip<F> {
+    pub fn construct(config: PoseidonConfig) -> Self {
+        Self {
+            config,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Configures the gates for hashing t - 1 inputs.
+    pub fn configure(meta: &mut ConstraintSystem<F>, t: usize) -> PoseidonConfig {
+        assert!((2..=17).contains(&t), "Invalid parameters!");
+
+        let state: Vec<Column<Advice>> = (0..t).map(|_| meta.advice_column()).collect();
+        let c: Vec<Column<Fixed>> = (0..t).map(|_| meta.fixed_column()).collect();
+        let s: Vec<Column<Fixed>> = (0..t * 2 - 1).map(|_| meta.fixed_column()).collect();
+        let ark = meta.selector();
+        let full = meta.selector();
+        let full_p = meta.selector();
+        let partial = meta.selector();
+
+        for column in &state {
+            meta.enable_equality(*column);
+        }
+
+        let params = F::params(t).expect("Invalid parameters!");
+
+        meta.create_gate("ark", |meta| {
+            let selector = meta.query_selector(ark);
+
+            (0..t)
+                .map(|i| {
+                    let cur = meta.query_advice(state[i], Rotation::cur());
+                    let next = meta.query_advice(state[i], Rotation::next());
+                    let c = meta.query_fixed(c[i], Rotation::cur());
+                    selector.clone() * (next - (cur + c))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // Sigma on every element, ark and mix with the given matrix.
+        let mut full_round = |name: &'static str, selector: Selector, m: &[Vec<F>]| {
+            meta.create_gate(name, |meta| {
+                let selector = meta.query_selector(selector);
+                let sbox: Vec<Expression<F>> = (0..t)
+                    .map(|j| {
+                        let cur = meta.query_advice(state[j], Rotation::cur());
+                        let c = meta.query_fixed(c[j], Rotation::cur());
+                        sigma(cur) + c
+                    })
+                    .collect();
+
+                (0..t)
+                    .map(|i| {
+                        let next = meta.query_advice(state[i], Rotation::next());
+                        let lc = (0..t).fold(Expression::Constant(F::ZERO), |acc, j| {
+                            acc + sbox[j].clone() * m[j][i]
+                        });
+                        selector.clone() * (next - lc)
+                    })
+                    .collect::<Vec<_>>()
+            });
+        };
+        full_round("full round", full, &params.m);
+        full_round("full round p", full_p, &params.p);
+
+        // Sigma on the first element, ark on the first element and mix with the sparse matrix.
+        meta.create_gate("partial round", |meta| {
+            let selector = meta.query_selector(partial);
+            let cur: Vec<Expression<F>> = (0..t)
+                .map(|j| meta.query_advice(state[j], Rotation::cur()))
+                .collect();
+            let next: Vec<Expression<F>> = (0..t)
+                .map(|j| meta.query_advice(state[j], Rotation::next()))
+                .collect();
+            let s: Vec<Expression<F>> = (0..t * 2 - 1)
+                .map(|j| meta.query_fixed(s[j], Rotation::cur()))
+                .collect();
+            let sbox = sigma(cur[0].clone()) + meta.query_fixed(c[0], Rotation::cur());
+
+            let mut lc = s[0].clone() * sbox.clone();
+            for i in 1..t {
+                lc = lc + s[i].clone() * cur[i].clone();
+            }
+
+            let mut constraints = vec![selector.clone() * (next[0].clone() - lc)];
+            for i in 1..t {
+                let lc = cur[i].clone() + sbox.clone() * s[t + i - 1].clone();
+                constraints.push(selector.clone() * (next[i].clone() - lc));
+            }
+            constraints
+        });
+
+        PoseidonConfig {
+            state,
+            c,
+            s,
+            ark,
+            full,
+            full_p,
+            partial,
+            t,
+        }
+    }
+
+    /// Hash arithmetics, constrains the first n_outs elements of the final state.
+    pub fn poseidon_ex(
+        &self,
+        mut layouter: impl Layouter<F>,
+        inputs: &[AssignedCell<F, F>],
+        initial_state: &AssignedCell<F, F>,
+        n_outs: usize,
+    ) -> Result<Vec<AssignedCell<F, F>>, Error> {
+        let config = &self.config;
+        let t = config.t;
+        assert_eq!(inputs.len() + 1, t, "Invalid parameters!");
+        assert!((1..=t).contains(&n_outs), "Invalid parameters!");
+
+        let params = F::params(t).expect("Invalid parameters!");
+        let n_rounds_f = params.n_rounds_f;
+        let n_round_p = params.n_rounds_p;
+        let (c, s, m, p) = (&params.c, &params.s, &params.m, &params.p);
+
+        // Every row applies one component to the state, the last row holds the outputs.
+        let mut rows = vec![Row::new(config.ark, c[..t].to_vec(), None)];
+
+        for r in 0..n_rounds_f / 2 - 1 {
+            let row_c = c[(r + 1) * t..(r + 2) * t].to_vec();
+            rows.push(Row::new(config.full, row_c, None));
+        }
+
+        let offset = (n_rounds_f / 2) * t;
+        rows.push(Row::new(
+            config.full_p,
+            c[offset..offset + t].to_vec(),
+            None,
+        ));
+
+        for r in 0..n_round_p {
+            let mut row_c = vec![F::ZERO; t];
+            row_c[0] = c[(n_rounds_f / 2 + 1) * t + r];
+            let row_s = &s[(t * 2 - 1) * r..(t * 2 - 1) * (r + 1)];
+            rows.push(Row::new(config.partial, row_c, Some(row_s)));
+        }
+
+        for r in 0..n_rounds_f / 2 - 1 {
+            let offset = (n_rounds_f / 2 + 1) * t + n_round_p + r * t;
+            rows.push(Row::new(config.full, c[offset..offset + t].to_vec(), None));
+        }
+
+        rows.push(Row::new(config.full, vec![F::ZERO; t], None));
+
+        layouter.assign_region(
+            || "poseidon",
+            |mut region| {
+                let mut state = Vec::with_capacity(t);
+                state.push(initial_state.copy_advice(
+                    || "initial state",
+                    &mut region,
+                    config.state[0],
+                    0,
+                )?);
+                for (j, input) in inputs.iter().enumerate() {
+                    state.push(input.copy_advice(
+                        || "input",
+                        &mut region,
+                        config.state[j + 1],
+                        0,
+                    )?);
+                }
+
+                for (offset, row) in rows.iter().enumerate() {
+                    row.selector.enable(&mut region, offset)?;
+
+                    for (j, item) in row.c.iter().enumerate() {
+                        region.assign_fixed(|| "c", config.c[j], offset, || Value::known(*item))?;
+                    }
+
+                    if let Some(row_s) = row.s {
+                        for (j, item) in row_s.iter().enumerate() {
+                            region.assign_fixed(
+                                || "s",
+                                config.s[j],
+                                offset,
+                                || Value::known(*item),
+                            )?;
+                        }
+                    }
+
+                    let cur: Value<Vec<F>> =
+                        state.iter().map(|cell| cell.value().copied()).collect();
+                    let next = cur.map(|cur| {
+                        if row.selector == config.ark {
+                            (0..t).map(|i| cur[i] + row.c[i]).collect()
+                        } else if row.selector == config.partial {
+                            let row_s = row.s.unwrap();
+                            let sbox = sigma_native(cur[0]) + row.c[0];
+                            let mut next = vec![row_s[0] * sbox; t];
+                            for i in 1..t {
+                                next[0] += row_s[i] * cur[i];
+                            }
+                            for i in 1..t {
+                                next[i] = cur[i] + sbox * row_s[t + i - 1];
+                            }
+                            next
+                        } else {
+                            let m = if row.selector == config.full_p { p } else { m };
+                            let sbox: Vec<F> =
+                                (0..t).map(|j| sigma_native(cur[j]) + row.c[j]).collect();
+                            (0..t)
+                                .map(|i| (0..t).fold(F::ZERO, |acc, j| acc + m[j][i] * sbox[j]))
+                                .collect()
+                        }
+                    });
+
+                    state = (0..t)
+                        .map(|i| {
+                            region.assign_advice(
+                                || "state",
+                                config.state[i],
+                                offset + 1,
+                                || next.as_ref().map(|next| next[i]),
+                            )
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+                }
+
+                state.truncate(n_outs);
+                Ok(state)
+            },
+        )
+    }
+}
+
+/// Selector and constants of one row of the layout.
+struct Row<'a, F> {
+    selector: Selector,
+    c: Vec<F>,
+    s: Option<&'a [F]>,
+}
+
+impl<'a, F> Row<'a, F> {
+    fn new(selector: Selector, c: Vec<F>, s: Option<&'a [F]>) -> Self {
+        Self { selector, c, s }
+    }
+}
+
+/// Exp of S-box as an expression.
+fn sigma<F: PoseidonField>(item: Expression<F>) -> Expression<F> {
+    let item_2 = item.clone() * item.clone();
+    let item_4 = item_2.clone() * item_2;
+    item * item_4
+}
+
+/// Exp of S-box.
+fn sigma_native<F: PoseidonField>(item: F) -> F {
+    let item_2 = item * item;
+    let item_4 = item_2 * item_2;
+    item * item_4
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadgets::templates::Poseidon;
+    use halo2::{
+        circuit::SimpleFloorPlanner,
+        dev::MockProver,
+        halo2curves::pasta,
+        plonk::{Circuit, Instance},
+    };
+
+    #[derive(Clone, Debug)]
+    struct TestConfig {
+        poseidon: PoseidonConfig,
+        instance: Column<Instance>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuit<F, const T: usize> {
+        inputs: Vec<Value<F>>,
+        initial_state: Value<F>,
+        n_outs: usize,
+    }
+
+    impl<F: PoseidonField, const T: usize> Circuit<F> for TestCircuit<F, T> {
+        type Config = TestConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            Self {
+                inputs: vec![Value::unknown(); T - 1],
+                initial_state: Value::unknown(),
+                n_outs: self.n_outs,
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> TestConfig {
+            let poseidon = PoseidonChip::<F>::configure(meta, T);
+            let instance = meta.instance_column();
+            meta.enable_equality(instance);
+
+            TestConfig { poseidon, instance }
+        }
+
+        fn synthesize(
+            &self,
+            config: TestConfig,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let (initial_state, inputs) = layouter.assign_region(
+                || "load inputs",
+                |mut region| {
+                    let state = &config.poseidon.state;
+                    let initial_state = region.assign_advice(
+                        || "initial state",
+                        state[0],
+                        0,
+                        || self.initial_state,
+                    )?;
+                    let inputs = self
+                        .inputs
+                        .iter()
+                        .enumerate()
+                        .map(|(j, item)| {
+                            region.assign_advice(|| "input", state[j + 1], 0, || *item)
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    Ok((initial_state, inputs))
+                },
+            )?;
+
+            let chip = PoseidonChip::construct(config.poseidon);
+            let outputs = chip.poseidon_ex(
+                layouter.namespace(|| "poseidon"),
+                &inputs,
+                &initial_state,
+                self.n_outs,
+            )?;
+
+            for (i, output) in outputs.iter().enumerate() {
+                layouter.constrain_instance(output.cell(), config.instance, i)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn run<F: PoseidonField, const T: usize>() {
+        let inputs: Vec<F> = (0..T - 1).map(|i| F::from(i as u64 + 1)).collect();
+        let initial_state = F::from(T as u64);
+        let n_outs = T;
+
+        let poseidon = Poseidon::new(inputs.clone());
+        let expected = poseidon.poseidon_ex_multi(initial_state, n_outs).unwrap();
+
+        let circuit = TestCircuit::<F, T> {
+            inputs: inputs.iter().map(|item| Value::known(*item)).collect(),
+            initial_state: Value::known(initial_state),
+            n_outs,
+        };
+
+        let k = 7;
+        let prover = MockProver::run(k, &circuit, vec![expected.clone()]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let mut wrong = expected;
+        wrong[0] += F::ONE;
+        let prover = MockProver::run(k, &circuit, vec![wrong]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+
+    #[test]
+    fn test_poseidon_chip() {
+        run::<Fr, 2>();
+        run::<Fr, 3>();
+        run::<Fr, 4>();
+        run::<Fr, 5>();
+        run::<Fr, 6>();
+        run::<Fr, 7>();
+        run::<Fr, 8>();
+        run::<Fr, 9>();
+        run::<Fr, 10>();
+        run::<Fr, 11>();
+        run::<Fr, 12>();
+        run::<Fr, 13>();
+        run::<Fr, 14>();
+        run::<Fr, 15>();
+        run::<Fr, 16>();
+        run::<Fr, 17>();
+    }
+
+    #[test]
+    fn test_poseidon_chip_pasta() {
+        run::<pasta::Fp, 3>();
+        run::<pasta::Fq, 5>();
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Errors returned by the parsing, parameter and hashing functions. New variants may be added,
+/// so matches need a wildcard arm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PoseidonError {
+    /// The string is not valid hex.
+    InvalidHex(String),
+    /// The string is not a valid decimal number.
+    InvalidDecimal(String),
+    /// The value is not below the field modulus.
+    NonCanonical(String),
+    /// The number of inputs (width - 1) has no parameters.
+    UnsupportedArity(usize),
+    /// The number of outputs is not in 1..=t.
+    InvalidOutputs { n_outs: usize, t: usize },
+    /// The hex string does not start with `0x`.
+    MissingPrefix(String),
+    /// Custom parameters have the wrong shape or a singular matrix.
+    InvalidParameters,
+    /// The tree depth is not supported.
+    InvalidDepth(usize),
+    /// The tree has no room for another leaf.
+    TreeFull,
+    /// The leaf index is not in the tree.
+    IndexOutOfRange { index: usize, len: usize },
+    /// The key is already in the tree.
+    KeyExists,
+    /// The key is not in the tree.
+    KeyNotFound,
+    /// Reading or writing the node storage failed.
+    Storage(String),
+    /// A checkpoint with the name already exists.
+    CheckpointExists(String),
+    /// There is no checkpoint with the name.
+    UnknownCheckpoint(String),
+    /// The serialized proof is malformed.
+    MalformedProof(String),
+}
+
+impl fmt::Display for PoseidonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHex(item) => write!(f, "invalid hex string {:?}", item),
+            Self::InvalidDecimal(item) => write!(f, "invalid decimal string {:?}", item),
+            Self::NonCanonical(item) => write!(f, "value {:?} is not below the modulus", item),
+            Self::UnsupportedArity(n) => write!(f, "unsupported number of inputs {}", n),
+            Self::InvalidOutputs { n_outs, t } => {
+                write!(f, "number of outputs {} is not in 1..={}", n_outs, t)
+            }
+            Self::MissingPrefix(item) => write!(f, "hex string {:?} has no 0x prefix", item),
+            Self::InvalidParameters => write!(f, "invalid parameters"),
+            Self::InvalidDepth(depth) => write!(f, "unsupported tree depth {}", depth),
+            Self::TreeFull => write!(f, "tree is full"),
+            Self::IndexOutOfRange { index, len } => {
+                write!(f, "index {} is out of range for {} leaves", index, len)
+            }
+            Self::KeyExists => write!(f, "key is already in the tree"),
+            Self::KeyNotFound => write!(f, "key is not in the tree"),
+            Self::Storage(item) => write!(f, "storage error: {}", item),
+            Self::CheckpointExists(name) => write!(f, "checkpoint {:?} already exists", name),
+            Self::UnknownCheckpoint(name) => write!(f, "unknown checkpoint {:?}", name),
+            Self::MalformedProof(item) => write!(f, "malformed proof: {}", item),
+        }
+    }
+}
+
+impl std::error::Error for PoseidonError {}
+
+impl From<std::io::Error> for PoseidonError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Storage(error.to_string())
+    }
+}
@@ -0,0 +1,612 @@
+// Rust port of the reference script https://extgit.iaik.tugraz.at/krypto/hadeshash/-/blob/master/code/generate_parameters_grain.sage
+// Round constants and the mds matrix are sampled from a Grain LFSR in self-shrinking mode,
+// seeded with the field, S-box, field size, width and round counts.
+
+use std::collections::VecDeque;
+
+use super::*;
+#[cfg(test)]
+use halo2::halo2curves::bn256::Fr;
+use halo2::halo2curves::ff::PrimeField;
+use num_bigint::BigUint;
+use optimize::{identity, mul, mul_vec};
+use utils::{modulus, reduce};
+
+/// Field type of the script, 1 is GF(p).
+const FIELD_PRIME: u64 = 1;
+
+/// Grain LFSR in self-shrinking mode.
+#[derive(Clone, Debug)]
+pub struct Grain {
+    state: VecDeque<bool>,
+}
+
+impl Grain {
+    /// Seeds the LFSR with the parameters and discards the first 160 bits.
+    pub fn new(
+        field: u64,
+        sbox: u64,
+        n: usize,
+        t: usize,
+        n_rounds_f: usize,
+        n_rounds_p: usize,
+    ) -> Self {
+        let mut state = VecDeque::with_capacity(80);
+        let params = [
+            (field, 2),
+            (sbox, 4),
+            (n as u64, 12),
+            (t as u64, 12),
+            (n_rounds_f as u64, 10),
+            (n_rounds_p as u64, 10),
+        ];
+
+        for (value, bits) in params {
+            for i in (0..bits).rev() {
+                state.push_back((value >> i) & 1 == 1);
+            }
+        }
+
+        state.extend([true; 30]);
+
+        let mut grain = Self { state };
+        for _ in 0..160 {
+            grain.update();
+        }
+
+        grain
+    }
+
+    /// Shifts the register once and returns the new bit.
+    fn update(&mut self) -> bool {
+        let s = &self.state;
+        let new_bit = s[62] ^ s[51] ^ s[38] ^ s[23] ^ s[13] ^ s[0];
+        self.state.pop_front();
+        self.state.push_back(new_bit);
+        new_bit
+    }
+
+    /// Returns the next output bit, pairs starting with 0 are discarded.
+    pub fn next_bit(&mut self) -> bool {
+        loop {
+            let first = self.update();
+            let second = self.update();
+            if first {
+                return second;
+            }
+        }
+    }
+
+    /// Returns the next n bits as an integer, most significant bit first.
+    pub fn next_bits(&mut self, n: usize) -> BigUint {
+        let mut value = BigUint::from(0u64);
+        for _ in 0..n {
+            value <<= 1;
+            if self.next_bit() {
+                value += 1u64;
+            }
+        }
+        value
+    }
+
+    /// Returns the next integer below the modulus, sampling again otherwise.
+    pub fn next_field_element<F: PrimeField>(&mut self) -> F {
+        let modulus = modulus::<F>();
+        loop {
+            let value = self.next_bits(F::NUM_BITS as usize);
+            if value < modulus {
+                return reduce(&value);
+            }
+        }
+    }
+
+    /// Returns the next n bits reduced modulo the field.
+    pub fn next_field_element_reduced<F: PrimeField>(&mut self) -> F {
+        reduce(&self.next_bits(F::NUM_BITS as usize))
+    }
+}
+
+/// Returns the round constants (R_F + R_P) * t and the t x t Cauchy mds matrix generated by
+/// the reference script for x^5 (sbox = 0) or x^-1 (sbox = 1).
+/// Matrices failing the script's invariant subspace checks are resampled, as in the script.
+/// The matrix is returned as in the script, circomlib stores its transpose.
+pub fn generate_parameters<F: PrimeField>(
+    sbox: u64,
+    t: usize,
+    n_rounds_f: usize,
+    n_rounds_p: usize,
+) -> (Vec<F>, Vec<Vec<F>>) {
+    let n = F::NUM_BITS as usize;
+    let mut grain = Grain::new(FIELD_PRIME, sbox, n, t, n_rounds_f, n_rounds_p);
+
+    let c: Vec<F> = (0..(n_rounds_f + n_rounds_p) * t)
+        .map(|_| grain.next_field_element())
+        .collect();
+
+    let m = loop {
+        let m = create_mds(&mut grain, t);
+        if is_secure_mds(&m) {
+            break m;
+        }
+    };
+
+    (c, m)
+}
+
+/// Samples distinct x_i and y_j and returns the Cauchy matrix 1 / (x_i + y_j).
+fn create_mds<F: PrimeField>(grain: &mut Grain, t: usize) -> Vec<Vec<F>> {
+    loop {
+        let rand_list: Vec<F> = loop {
+            let rand_list: Vec<F> = (0..t * 2)
+                .map(|_| grain.next_field_element_reduced())
+                .collect();
+            let distinct = (0..t * 2).all(|i| (i + 1..t * 2).all(|j| rand_list[i] != rand_list[j]));
+            if distinct {
+                break rand_list;
+            }
+        };
+
+        let (xs, ys) = rand_list.split_at(t);
+        let m: Option<Vec<Vec<F>>> = xs
+            .iter()
+            .map(|x| ys.iter().map(|y| Option::from((*x + y).invert())).collect())
+            .collect();
+
+        if let Some(m) = m {
+            return m;
+        }
+    }
+}
+
+/// Checks the mds matrix against infinitely long invariant subspace trails, with the
+/// algorithms 1, 2 and 3 of the reference script.
+pub fn is_secure_mds<F: PrimeField>(m: &[Vec<F>]) -> bool {
+    let t = m.len();
+    assert!(
+        t >= 2 && m.iter().all(|row| row.len() == t),
+        "Invalid parameters!"
+    );
+
+    algorithm_1(m) && algorithm_2(m) && algorithm_3(m)
+}
+
+/// Checks that no power M^i, i < t, has an invariant subspace of the inputs left active by
+/// i - 1 partial rounds.
+fn algorithm_1<F: PrimeField>(m: &[Vec<F>]) -> bool {
+    let t = m.len();
+    let mut powers = vec![m.to_vec()];
+    for i in 1..t {
+        powers.push(mul(&powers[i - 1], m));
+    }
+
+    for i in 1..t {
+        let mat = &powers[i - 1];
+        let scalar =
+            (0..t).all(|j| (0..t).all(|k| mat[j][k] == if j == k { mat[0][0] } else { F::ZERO }));
+        if scalar {
+            return false;
+        }
+
+        // Vectors with a zero first element staying so through i - 1 rounds.
+        let s = if i == 1 {
+            identity(t)[1..].to_vec()
+        } else {
+            let rows: Vec<Vec<F>> = powers[..i - 1]
+                .iter()
+                .map(|power| power[0][1..].to_vec())
+                .collect();
+            kernel(&rows, t - 1)
+                .into_iter()
+                .map(|vector| [vec![F::ZERO], vector].concat())
+                .collect()
+        };
+
+        let invariant: usize = roots(&charpoly(mat))
+            .into_iter()
+            .map(|root| {
+                let shifted: Vec<Vec<F>> = mat
+                    .iter()
+                    .enumerate()
+                    .map(|(j, row)| {
+                        let mut row = row.clone();
+                        row[j] -= root;
+                        row
+                    })
+                    .collect();
+                let eigenspace = kernel(&shifted, t);
+                s.len() + eigenspace.len() - rank(&[s.clone(), eigenspace].concat())
+            })
+            .sum();
+        if invariant >= 1 {
+            return false;
+        }
+
+        for power in &powers[..i] {
+            let image: Vec<Vec<F>> = s.iter().map(|vector| mul_vec(power, vector)).collect();
+            if rank(&image) == s.len() && rank(&[s.clone(), image].concat()) == s.len() {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Checks that the subspace generated by the first unit vector under M is the whole space.
+fn algorithm_2<F: PrimeField>(m: &[Vec<F>]) -> bool {
+    let t = m.len();
+    let mut vector: Vec<F> = identity(t).swap_remove(0);
+    let mut basis: Vec<(usize, Vec<F>)> = Vec::with_capacity(t);
+    loop {
+        // Reduces the vector against the basis, pivots being normalized to one.
+        let mut reduced = vector.clone();
+        for (pivot, row) in &basis {
+            let factor = reduced[*pivot];
+            for (item, x) in reduced.iter_mut().zip(row) {
+                *item -= factor * x;
+            }
+        }
+
+        match reduced.iter().position(|item| !bool::from(item.is_zero())) {
+            Some(pivot) => {
+                let factor = reduced[pivot].invert().unwrap();
+                reduced.iter_mut().for_each(|item| *item *= factor);
+                basis.push((pivot, reduced));
+            }
+            None => return false,
+        }
+        if basis.len() == t {
+            return true;
+        }
+        vector = mul_vec(m, &vector);
+    }
+}
+
+/// Checks algorithm 2 for the powers M^2 to M^(4t).
+fn algorithm_3<F: PrimeField>(m: &[Vec<F>]) -> bool {
+    let mut power = m.to_vec();
+    for _ in 2..=m.len() * 4 {
+        power = mul(&power, m);
+        if !algorithm_2(&power) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Reduces the vectors to row echelon form, returning the nonzero rows and their pivots.
+fn echelon<F: PrimeField>(vectors: &[Vec<F>]) -> (Vec<Vec<F>>, Vec<usize>) {
+    let mut rows = vectors.to_vec();
+    let mut pivots = Vec::new();
+    let n_cols = rows.first().map_or(0, |row| row.len());
+
+    for col in 0..n_cols {
+        let r = pivots.len();
+        let pivot = match (r..rows.len()).find(|&row| !bool::from(rows[row][col].is_zero())) {
+            Some(pivot) => pivot,
+            None => continue,
+        };
+        rows.swap(r, pivot);
+
+        let factor = rows[r][col].invert().unwrap();
+        for item in rows[r].iter_mut() {
+            *item *= factor;
+        }
+        let pivot_row = rows[r].clone();
+        for (i, row) in rows.iter_mut().enumerate() {
+            if i != r {
+                let factor = row[col];
+                for (item, x) in row.iter_mut().zip(&pivot_row) {
+                    *item -= factor * x;
+                }
+            }
+        }
+        pivots.push(col);
+    }
+
+    rows.truncate(pivots.len());
+    (rows, pivots)
+}
+
+/// Returns the dimension of the space spanned by the vectors.
+fn rank<F: PrimeField>(vectors: &[Vec<F>]) -> usize {
+    echelon(vectors).1.len()
+}
+
+/// Returns a basis of the vectors v of length n with a * v = 0.
+fn kernel<F: PrimeField>(a: &[Vec<F>], n: usize) -> Vec<Vec<F>> {
+    let (rows, pivots) = echelon(a);
+    (0..n)
+        .filter(|col| !pivots.contains(col))
+        .map(|free| {
+            let mut vector = vec![F::ZERO; n];
+            vector[free] = F::ONE;
+            for (row, pivot) in rows.iter().zip(&pivots) {
+                vector[*pivot] = -row[free];
+            }
+            vector
+        })
+        .collect()
+}
+
+/// Returns the characteristic polynomial, lowest coefficient first, reducing the matrix to
+/// Hessenberg form (Cohen, algorithm 2.2.9).
+fn charpoly<F: PrimeField>(a: &[Vec<F>]) -> Vec<F> {
+    let n = a.len();
+    let mut h = a.to_vec();
+    for k in 0..n.saturating_sub(2) {
+        let pivot = match (k + 1..n).find(|&i| !bool::from(h[i][k].is_zero())) {
+            Some(pivot) => pivot,
+            None => continue,
+        };
+        h.swap(pivot, k + 1);
+        for row in h.iter_mut() {
+            row.swap(pivot, k + 1);
+        }
+
+        let pivot_inv = h[k + 1][k].invert().unwrap();
+        for j in k + 2..n {
+            let u = h[j][k] * pivot_inv;
+            if bool::from(u.is_zero()) {
+                continue;
+            }
+            let pivot_row = h[k + 1].clone();
+            for (item, x) in h[j].iter_mut().zip(&pivot_row) {
+                *item -= u * x;
+            }
+            for row in h.iter_mut() {
+                let item = row[j];
+                row[k + 1] += u * item;
+            }
+        }
+    }
+
+    let mut polys: Vec<Vec<F>> = vec![vec![F::ONE]];
+    for m in 1..=n {
+        // (x - h[m - 1][m - 1]) * p[m - 1]
+        let mut p = vec![F::ZERO; m + 1];
+        for (i, item) in polys[m - 1].iter().enumerate() {
+            p[i + 1] += item;
+            p[i] -= h[m - 1][m - 1] * item;
+        }
+
+        let mut product = F::ONE;
+        for i in 1..m {
+            product *= h[m - i][m - i - 1];
+            let factor = product * h[m - i - 1][m - 1];
+            for (j, item) in polys[m - i - 1].iter().enumerate() {
+                p[j] -= factor * item;
+            }
+        }
+        polys.push(p);
+    }
+
+    polys.swap_remove(n)
+}
+
+/// Returns the distinct roots of the polynomial in the field.
+fn roots<F: PrimeField>(f: &[F]) -> Vec<F> {
+    let f = trim(f.to_vec());
+    if f.len() < 2 {
+        return Vec::new();
+    }
+
+    // The roots are those of gcd(f, x^p - x).
+    let p = modulus::<F>();
+    let mut x_p = pow_mod(&[F::ZERO, F::ONE], &p, &f);
+    x_p.resize(x_p.len().max(2), F::ZERO);
+    x_p[1] -= F::ONE;
+    split(gcd(f, trim(x_p)), &((p - 1u64) >> 1))
+}
+
+/// Splits a product of distinct linear factors with gcd(g, (x + a)^((p - 1) / 2) - 1).
+fn split<F: PrimeField>(g: Vec<F>, exp: &BigUint) -> Vec<F> {
+    match g.len() {
+        0 | 1 => return Vec::new(),
+        2 => return vec![-g[0] * g[1].invert().unwrap()],
+        _ => {}
+    }
+
+    let mut a = F::ZERO;
+    loop {
+        let mut h = pow_mod(&[a, F::ONE], exp, &g);
+        h.resize(h.len().max(1), F::ZERO);
+        h[0] -= F::ONE;
+        let d = gcd(g.clone(), trim(h));
+        if d.len() > 1 && d.len() < g.len() {
+            let (q, _) = div_rem(&g, &d);
+            return [split(d, exp), split(q, exp)].concat();
+        }
+        a += F::ONE;
+    }
+}
+
+/// Removes the leading zero coefficients.
+fn trim<F: PrimeField>(mut f: Vec<F>) -> Vec<F> {
+    while f.last().is_some_and(|item| bool::from(item.is_zero())) {
+        f.pop();
+    }
+    f
+}
+
+/// Returns the quotient and remainder of a / b, b nonzero.
+fn div_rem<F: PrimeField>(a: &[F], b: &[F]) -> (Vec<F>, Vec<F>) {
+    let mut r = trim(a.to_vec());
+    let b = trim(b.to_vec());
+    let lead_inv = b.last().unwrap().invert().unwrap();
+    if r.len() < b.len() {
+        return (Vec::new(), r);
+    }
+
+    let mut q = vec![F::ZERO; r.len() - b.len() + 1];
+    while r.len() >= b.len() {
+        let shift = r.len() - b.len();
+        let factor = *r.last().unwrap() * lead_inv;
+        q[shift] = factor;
+        for (i, item) in b.iter().enumerate() {
+            r[shift + i] -= factor * item;
+        }
+        r = trim(r);
+    }
+    (q, r)
+}
+
+/// Returns the monic gcd of the polynomials.
+fn gcd<F: PrimeField>(mut a: Vec<F>, mut b: Vec<F>) -> Vec<F> {
+    while !b.is_empty() {
+        let (_, r) = div_rem(&a, &b);
+        a = b;
+        b = r;
+    }
+    if let Some(lead) = a.last() {
+        let lead_inv = lead.invert().unwrap();
+        for item in a.iter_mut() {
+            *item *= lead_inv;
+        }
+    }
+    a
+}
+
+/// Returns base^exp mod f, f monic.
+fn pow_mod<F: PrimeField>(base: &[F], exp: &BigUint, f: &[F]) -> Vec<F> {
+    let n = f.len() - 1;
+    let rem = |mut a: Vec<F>| {
+        while a.len() > n {
+            let lead = a.pop().unwrap();
+            let shift = a.len() - n;
+            for (item, x) in a[shift..].iter_mut().zip(f) {
+                *item -= lead * x;
+            }
+        }
+        a
+    };
+    let mul_mod = |a: &[F], b: &[F]| {
+        let mut product = vec![F::ZERO; (a.len() + b.len()).saturating_sub(1)];
+        for (i, x) in a.iter().enumerate() {
+            for (j, y) in b.iter().enumerate() {
+                product[i + j] += *x * y;
+            }
+        }
+        rem(product)
+    };
+
+    let base = rem(base.to_vec());
+    let mut result = vec![F::ONE];
+    for i in (0..exp.bits()).rev() {
+        result = mul_mod(&result, &result);
+        if exp.bit(i) {
+            result = mul_mod(&result, &base);
+        }
+    }
+    trim(result)
+}
+
+/// BN254 parameters generated for every width with circomlib's round numbers, shared by the
+/// tests as the checks make generating them slow in debug builds.
+#[cfg(test)]
+pub(crate) fn bn254_parameters(t: usize) -> &'static (Vec<Fr>, Vec<Vec<Fr>>) {
+    use constants::{N_ROUNDS_F, N_ROUNDS_P};
+    use std::sync::OnceLock;
+
+    type Parameters = (Vec<Fr>, Vec<Vec<Fr>>);
+    static PARAMETERS: [OnceLock<Parameters>; 16] = [const { OnceLock::new() }; 16];
+    PARAMETERS[t - 2].get_or_init(|| generate_parameters(0, t, N_ROUNDS_F, N_ROUNDS_P[t - 2]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadgets::{
+        constants::{N_ROUNDS_F, N_ROUNDS_P},
+        utils::{hex_to_field, poseidon_c, poseidon_m},
+    };
+    use halo2::halo2curves::bn256::Fr;
+
+    #[test]
+    fn test_grain_constants() {
+        // First round constant for t = 3, also the first entry of circomlib's C.
+        let (c, _) = bn254_parameters(3);
+        let expected =
+            hex_to_field("0x0ee9a592ba9a9518d05986d656f40c2114c4993c11bb29938d21d47304cd8e6e")
+                .unwrap();
+
+        assert_eq!(c.len(), (N_ROUNDS_F + N_ROUNDS_P[1]) * 3);
+        assert_eq!(c[0], expected);
+    }
+
+    #[test]
+    fn test_grain_shipped_parameters() {
+        for t in 2..=17 {
+            let (c, m) = bn254_parameters(t);
+
+            // The first round constants are not changed by the optimization.
+            assert_eq!(c[..t], poseidon_c(t)[..t]);
+
+            let poseidon_m = poseidon_m(t);
+            for i in 0..t {
+                for j in 0..t {
+                    assert_eq!(m[i][j], poseidon_m[j][i]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_grain_mds_checks() {
+        let matrix = |rows: &[&[u64]]| -> Vec<Vec<Fr>> {
+            rows.iter()
+                .map(|row| row.iter().map(|item| Fr::from(*item)).collect())
+                .collect()
+        };
+
+        // circomlib's matrices pass, they are stored transposed.
+        for t in [2, 3, 9, 17] {
+            let m = poseidon_m(t);
+            let m: Vec<Vec<Fr>> = (0..t).map(|i| (0..t).map(|j| m[j][i]).collect()).collect();
+            assert!(is_secure_mds(&m));
+        }
+
+        // A scalar matrix keeps every subspace (algorithm 1).
+        let scalar = matrix(&[&[2, 0], &[0, 2]]);
+        assert!(!algorithm_1(&scalar));
+
+        // The first unit vector is an eigenvector (algorithm 2).
+        let triangular = matrix(&[&[2, 1, 0], &[0, 3, 1], &[0, 0, 4]]);
+        assert!(!algorithm_2(&triangular));
+        assert!(!is_secure_mds(&triangular));
+
+        // The swap passes algorithms 1 and 2 but its square is the identity (algorithm 3).
+        let swap = matrix(&[&[0, 1], &[1, 0]]);
+        assert!(algorithm_1(&swap) && algorithm_2(&swap));
+        assert!(!algorithm_3(&swap));
+    }
+
+    #[test]
+    fn test_grain_roots() {
+        // (x - 1)(x - 2)(x - 3)(x^2 - g), x^2 - g having no root as the generator is not a square.
+        let a = matrix_diag(&[1, 2, 3]);
+        let mut f = charpoly(&a);
+        assert_eq!(f, [-Fr::from(6), Fr::from(11), -Fr::from(6), Fr::one()]);
+
+        let x2_g = [-Fr::MULTIPLICATIVE_GENERATOR, Fr::zero(), Fr::one()];
+        let mut product = vec![Fr::zero(); f.len() + 2];
+        for (i, x) in f.iter().enumerate() {
+            for (j, y) in x2_g.iter().enumerate() {
+                product[i + j] += *x * y;
+            }
+        }
+        f = product;
+
+        let mut roots = roots(&f);
+        roots.sort_by_key(|root| root.to_repr());
+        assert_eq!(roots, [Fr::from(1), Fr::from(2), Fr::from(3)]);
+    }
+
+    fn matrix_diag(items: &[u64]) -> Vec<Vec<Fr>> {
+        let mut m = vec![vec![Fr::zero(); items.len()]; items.len()];
+        for (i, item) in items.iter().enumerate() {
+            m[i][i] = Fr::from(*item);
+        }
+        m
+    }
+}
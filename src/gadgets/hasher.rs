@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use params::{PoseidonField, PoseidonParams};
+use templates::Poseidon;
+
+/// Hashes a fixed number of inputs with the parameters of one width, as circomlib's
+/// Poseidon(n) and PoseidonEx(n, n_outs).
+#[derive(Clone, Debug)]
+pub struct Hasher<F: 'static + Clone = Fr> {
+    params: Cow<'static, PoseidonParams<F>>,
+}
+
+impl<F: PoseidonField> Hasher<F> {
+    /// Creates a hasher for the given number of inputs with the field's parameters.
+    pub fn new(n_inputs: usize) -> Result<Self, PoseidonError> {
+        let params = Cow::Borrowed(F::params(n_inputs + 1)?);
+        Ok(Self { params })
+    }
+
+    /// Creates a hasher with custom parameters, hashing t - 1 inputs.
+    pub fn with_params(params: PoseidonParams<F>) -> Self {
+        let params = Cow::Owned(params);
+        Self { params }
+    }
+
+    /// Returns the parameters of the hasher.
+    pub fn params(&self) -> &PoseidonParams<F> {
+        &self.params
+    }
+
+    /// Returns Poseidon(inputs).
+    pub fn hash(&self, inputs: &[F]) -> Result<F, PoseidonError> {
+        Ok(self.hash_ex(inputs, F::ZERO, 1)?[0])
+    }
+
+    /// Returns the first n_outs elements of the final state of PoseidonEx(inputs, initial_state).
+    pub fn hash_ex(
+        &self,
+        inputs: &[F],
+        initial_state: F,
+        n_outs: usize,
+    ) -> Result<Vec<F>, PoseidonError> {
+        let poseidon = Poseidon::new(inputs.to_vec());
+        poseidon.poseidon_ex_multi_with_params(&self.params, initial_state, n_outs)
+    }
+}
+
+/// Returns Poseidon(inputs) with the field's parameters, as circomlibjs `poseidon(inputs)`.
+pub fn hash<F: PoseidonField>(inputs: &[F]) -> Result<F, PoseidonError> {
+    Hasher::new(inputs.len())?.hash(inputs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hasher() {
+        let inputs = [Fr::from(1), Fr::from(2)];
+        let expected = Poseidon::new(inputs.to_vec())
+            .poseidon_ex_multi(Fr::from(3), 3)
+            .unwrap();
+
+        let hasher = Hasher::new(2).unwrap();
+        assert_eq!(hasher.hash_ex(&inputs, Fr::from(3), 3).unwrap(), expected);
+        assert_eq!(hasher.hash(&inputs), hash(&inputs));
+
+        let hasher = Hasher::with_params(Fr::params(3).unwrap().clone());
+        assert_eq!(hasher.params().t(), 3);
+        assert_eq!(
+            hasher.hash_ex(&inputs, Fr::from(3), 2).unwrap(),
+            expected[..2]
+        );
+    }
+
+    #[test]
+    fn test_hasher_errors() {
+        assert_eq!(hash::<Fr>(&[]), Err(PoseidonError::UnsupportedArity(0)));
+        assert_eq!(
+            hash(&[Fr::one(); 17]),
+            Err(PoseidonError::UnsupportedArity(17))
+        );
+        assert_eq!(
+            Hasher::<Fr>::new(17).unwrap_err(),
+            PoseidonError::UnsupportedArity(17)
+        );
+
+        let hasher = Hasher::new(2).unwrap();
+        assert_eq!(
+            hasher.hash(&[Fr::one()]),
+            Err(PoseidonError::UnsupportedArity(1))
+        );
+        assert_eq!(
+            hasher.hash_ex(&[Fr::one(); 2], Fr::zero(), 0),
+            Err(PoseidonError::InvalidOutputs { n_outs: 0, t: 3 })
+        );
+        assert_eq!(
+            hasher.hash_ex(&[Fr::one(); 2], Fr::zero(), 4),
+            Err(PoseidonError::InvalidOutputs { n_outs: 4, t: 3 })
+        );
+    }
+}
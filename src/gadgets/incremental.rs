@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use merkle::{hash_pair, zeros, MAX_DEPTH};
+use params::PoseidonField;
+
+/// Number of roots kept by default, as Tornado's `MerkleTreeWithHistory`.
+pub const ROOT_HISTORY_SIZE: usize = 30;
+
+/// Append-only binary Merkle tree keeping only the frontier, as the Solidity
+/// `MerkleTreeWithHistory` contracts: the last filled left node of every level, the zeros of
+/// the empty subtrees and a ring buffer of the last roots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IncrementalMerkleTree<F = Fr> {
+    depth: usize,
+    zeros: Vec<F>,
+    filled_subtrees: Vec<F>,
+    roots: VecDeque<F>,
+    history_size: usize,
+    next_index: usize,
+}
+
+impl<F: PoseidonField> IncrementalMerkleTree<F> {
+    /// Creates an empty tree of the given depth whose empty leaves are `zero`.
+    pub fn new(depth: usize, zero: F) -> Result<Self, PoseidonError> {
+        Self::with_history_size(depth, zero, ROOT_HISTORY_SIZE)
+    }
+
+    /// Creates an empty tree keeping the given number of roots.
+    pub fn with_history_size(
+        depth: usize,
+        zero: F,
+        history_size: usize,
+    ) -> Result<Self, PoseidonError> {
+        if !(1..=MAX_DEPTH).contains(&depth) {
+            return Err(PoseidonError::InvalidDepth(depth));
+        }
+        if history_size == 0 {
+            return Err(PoseidonError::InvalidParameters);
+        }
+
+        let zeros = zeros(zero, depth);
+        let filled_subtrees = zeros[..depth].to_vec();
+        let mut roots = VecDeque::with_capacity(history_size);
+        roots.push_back(zeros[depth]);
+
+        Ok(Self {
+            depth,
+            zeros,
+            filled_subtrees,
+            roots,
+            history_size,
+            next_index: 0,
+        })
+    }
+
+    /// Returns the depth of the tree.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the index of the next leaf, the number of inserted leaves.
+    pub fn next_index(&self) -> usize {
+        self.next_index
+    }
+
+    /// Returns the zero of every level, from the empty leaf to the empty root.
+    pub fn zeros(&self) -> &[F] {
+        &self.zeros
+    }
+
+    /// Returns the last filled left node of every level.
+    pub fn filled_subtrees(&self) -> &[F] {
+        &self.filled_subtrees
+    }
+
+    /// Returns the current root, as `getLastRoot`.
+    pub fn root(&self) -> F {
+        *self.roots.back().expect("Invalid parameters!")
+    }
+
+    /// Returns the known roots, from the oldest to the current one.
+    pub fn roots(&self) -> impl Iterator<Item = &F> {
+        self.roots.iter()
+    }
+
+    /// Checks whether the root is one of the last roots, as `isKnownRoot`.
+    pub fn is_known_root(&self, root: F) -> bool {
+        self.roots.contains(&root)
+    }
+
+    /// Appends a leaf in O(depth) hashes and returns its index.
+    pub fn insert(&mut self, leaf: F) -> Result<usize, PoseidonError> {
+        let index = self.next_index;
+        if index == 1 << self.depth {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        let mut node = leaf;
+        for level in 0..self.depth {
+            node = if (index >> level) & 1 == 0 {
+                self.filled_subtrees[level] = node;
+                hash_pair(node, self.zeros[level])
+            } else {
+                hash_pair(self.filled_subtrees[level], node)
+            };
+        }
+
+        if self.roots.len() == self.history_size {
+            self.roots.pop_front();
+        }
+        self.roots.push_back(node);
+        self.next_index += 1;
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use merkle::MerkleTree;
+    use utils::{dec_to_field, hex_to_field};
+
+    #[test]
+    fn test_incremental_tree() {
+        // Tornado's zero leaf, keccak256("tornado") mod p.
+        let zero = dec_to_field::<Fr>(
+            "21663839004416932945382355908790599225266501822907911457504978515578255421292",
+        )
+        .unwrap();
+
+        let mut tree = IncrementalMerkleTree::new(5, zero).unwrap();
+        let mut expected = MerkleTree::new(5, zero).unwrap();
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.zeros(), expected.zeros());
+
+        for i in 0..20 {
+            let leaf = Fr::from(i * 3 + 1);
+            assert_eq!(tree.insert(leaf), expected.insert(leaf));
+            assert_eq!(tree.root(), expected.root());
+        }
+        assert_eq!(tree.next_index(), 20);
+    }
+
+    #[test]
+    fn test_incremental_tree_nova() {
+        // zeros(0..=4) of Tornado Nova's MerkleTreeWithHistory, the Poseidon version.
+        let expected: Vec<Fr> = [
+            "0x2fe54c60d3acabf3343a35b6eba15db4821b340f76e741e2249685ed4899af6c",
+            "0x13e37f2d6cb86c78ccc1788607c2b199788c6bb0a615a21f2e7a8e88384222f8",
+            "0x217126fa352c326896e8c2803eec8fd63ad50cf65edfef27a41a9e32dc622765",
+            "0x0e28a61a9b3e91007d5a9e3ada18e1b24d6d230c618388ee5df34cacd7397eee",
+            "0x27953447a6979839536badc5425ed15fadb0e292e9bc36f92f0aa5cfa5013587",
+        ]
+        .iter()
+        .map(|item| hex_to_field(item).unwrap())
+        .collect();
+
+        // The root of the empty tree is the constructor's zeros(levels).
+        let tree = IncrementalMerkleTree::new(4, expected[0]).unwrap();
+        assert_eq!(tree.zeros(), expected);
+        assert_eq!(tree.root(), expected[4]);
+    }
+
+    #[test]
+    fn test_incremental_tree_deposits() {
+        // Roots after the first deposits into a depth 4 tree with Nova's zero leaf, as
+        // MerkleTreeWithHistory's _insert updates filledSubtrees.
+        let zero =
+            hex_to_field("0x2fe54c60d3acabf3343a35b6eba15db4821b340f76e741e2249685ed4899af6c")
+                .unwrap();
+        let expected = [
+            "0x099a68ab349447121214ca8f9b99a11812a32d194b89f8597cefbb8bf6be2607",
+            "0x03a794710e5058ccfe92f9305693560ee418a4dc179525515f5d301c7315ce91",
+            "0x064d3a936ab53e64abc5cb970491b936c249e1b9c0fe59c6c6a2b6d7740f36c2",
+        ];
+
+        let mut tree = IncrementalMerkleTree::<Fr>::new(4, zero).unwrap();
+        for (i, root) in expected.iter().enumerate() {
+            tree.insert(Fr::from(i as u64 + 1)).unwrap();
+            assert_eq!(tree.root(), hex_to_field(root).unwrap());
+            assert!(tree.is_known_root(hex_to_field(root).unwrap()));
+        }
+
+        // Two deposits fill a depth 1 tree with zero leaves, whose root is circomlibjs
+        // poseidon([1, 2]).
+        let mut tree = IncrementalMerkleTree::new(1, Fr::zero()).unwrap();
+        tree.insert(Fr::from(1)).unwrap();
+        tree.insert(Fr::from(2)).unwrap();
+        let expected = dec_to_field::<Fr>(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+        assert_eq!(tree.root(), expected);
+    }
+
+    #[test]
+    fn test_incremental_tree_history() {
+        let mut tree = IncrementalMerkleTree::with_history_size(2, Fr::zero(), 3).unwrap();
+        let mut roots = vec![tree.root()];
+        for i in 0..4 {
+            tree.insert(Fr::from(i)).unwrap();
+            roots.push(tree.root());
+        }
+
+        // Only the last three roots are known.
+        assert!(!tree.is_known_root(roots[0]));
+        assert!(!tree.is_known_root(roots[1]));
+        assert!(roots[2..].iter().all(|root| tree.is_known_root(*root)));
+        assert_eq!(tree.roots().copied().collect::<Vec<_>>(), roots[2..]);
+
+        assert_eq!(tree.insert(Fr::one()), Err(PoseidonError::TreeFull));
+        assert_eq!(tree.root(), roots[4]);
+    }
+
+    #[test]
+    fn test_incremental_tree_errors() {
+        assert_eq!(
+            IncrementalMerkleTree::new(0, Fr::zero()),
+            Err(PoseidonError::InvalidDepth(0))
+        );
+        assert_eq!(
+            IncrementalMerkleTree::new(33, Fr::zero()),
+            Err(PoseidonError::InvalidDepth(33))
+        );
+        assert_eq!(
+            IncrementalMerkleTree::with_history_size(3, Fr::zero(), 0),
+            Err(PoseidonError::InvalidParameters)
+        );
+    }
+}
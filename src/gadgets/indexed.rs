@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use hasher::hash;
+use merkle::{MerkleProof, MerkleTree};
+use params::PoseidonField;
+use utils::field_to_bytes_be;
+
+/// Leaf of an indexed Merkle tree: a value and the next larger value of the tree with its
+/// index, zero for the largest value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexedLeaf<F = Fr> {
+    pub value: F,
+    pub next_index: usize,
+    pub next_value: F,
+}
+
+/// Non-membership proof of a value: the low leaf, whose value is below it and whose next value
+/// is above it or zero, and its inclusion proof.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedProof<F = Fr> {
+    pub low_leaf: IndexedLeaf<F>,
+    pub low_leaf_index: usize,
+    pub proof: MerkleProof<F>,
+}
+
+/// Witness of an insertion: the low leaf with its inclusion proof before the insertion, and
+/// the new leaf with its inclusion proof after the low leaf points to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedInsertion<F = Fr> {
+    pub low_leaf: IndexedLeaf<F>,
+    pub low_leaf_index: usize,
+    pub low_leaf_proof: MerkleProof<F>,
+    pub new_leaf: IndexedLeaf<F>,
+    pub new_leaf_index: usize,
+    pub new_leaf_proof: MerkleProof<F>,
+}
+
+/// Indexed Merkle tree, as Aztec's nullifier tree: the leaves form a linked list sorted by
+/// value and hash to Poseidon(value, next_index, next_value) in a binary Merkle tree with zero
+/// empty leaves. The first leaf is (0, 0, 0).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedMerkleTree<F = Fr> {
+    tree: MerkleTree<F>,
+    leaves: Vec<IndexedLeaf<F>>,
+    indices: BTreeMap<[u8; 32], usize>,
+}
+
+impl<F: PoseidonField> IndexedLeaf<F> {
+    /// Returns Poseidon(value, next_index, next_value).
+    pub fn hash(&self) -> F {
+        let next_index = F::from(self.next_index as u64);
+        hash(&[self.value, next_index, self.next_value]).expect("Invalid parameters!")
+    }
+
+    /// Checks that the value falls between the leaf value and the next one.
+    pub fn is_low_leaf(&self, value: F) -> bool {
+        let value = field_to_bytes_be(&value);
+        field_to_bytes_be(&self.value) < value
+            && (self.next_value == F::ZERO || value < field_to_bytes_be(&self.next_value))
+    }
+}
+
+impl<F: PoseidonField> IndexedMerkleTree<F> {
+    /// Creates a tree of the given depth holding the first leaf.
+    pub fn new(depth: usize) -> Result<Self, PoseidonError> {
+        let first = IndexedLeaf {
+            value: F::ZERO,
+            next_index: 0,
+            next_value: F::ZERO,
+        };
+        let tree = MerkleTree::with_leaves(depth, F::ZERO, &[first.hash()])?;
+
+        Ok(Self {
+            tree,
+            leaves: vec![first],
+            indices: BTreeMap::from([(field_to_bytes_be(&F::ZERO), 0)]),
+        })
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.tree.root()
+    }
+
+    /// Returns the leaves in insertion order.
+    pub fn leaves(&self) -> &[IndexedLeaf<F>] {
+        &self.leaves
+    }
+
+    /// Checks whether the value is in the tree.
+    pub fn contains(&self, value: F) -> bool {
+        self.indices.contains_key(&field_to_bytes_be(&value))
+    }
+
+    /// Returns the inclusion proof of the leaf at the given index.
+    pub fn proof(&self, index: usize) -> Result<MerkleProof<F>, PoseidonError> {
+        self.tree.proof(index)
+    }
+
+    /// Returns the non-membership proof of a value that is not in the tree.
+    pub fn non_membership_proof(&self, value: F) -> Result<IndexedProof<F>, PoseidonError> {
+        let low_leaf_index = self.low_leaf_index(value)?;
+        Ok(IndexedProof {
+            low_leaf: self.leaves[low_leaf_index],
+            low_leaf_index,
+            proof: self.tree.proof(low_leaf_index)?,
+        })
+    }
+
+    /// Inserts a value that is not in the tree, returning the low leaf and new leaf witnesses.
+    pub fn insert(&mut self, value: F) -> Result<IndexedInsertion<F>, PoseidonError> {
+        let low_leaf_index = self.low_leaf_index(value)?;
+        let new_leaf_index = self.leaves.len();
+        if new_leaf_index == self.tree.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        let low_leaf = self.leaves[low_leaf_index];
+        let low_leaf_proof = self.tree.proof(low_leaf_index)?;
+        let new_leaf = IndexedLeaf {
+            value,
+            next_index: low_leaf.next_index,
+            next_value: low_leaf.next_value,
+        };
+
+        let updated = IndexedLeaf {
+            next_index: new_leaf_index,
+            next_value: value,
+            ..low_leaf
+        };
+        self.leaves[low_leaf_index] = updated;
+        self.tree.update(low_leaf_index, updated.hash())?;
+
+        self.leaves.push(new_leaf);
+        self.indices
+            .insert(field_to_bytes_be(&value), new_leaf_index);
+        self.tree.insert(new_leaf.hash())?;
+
+        Ok(IndexedInsertion {
+            low_leaf,
+            low_leaf_index,
+            low_leaf_proof,
+            new_leaf,
+            new_leaf_index,
+            new_leaf_proof: self.tree.proof(new_leaf_index)?,
+        })
+    }
+
+    /// Inserts values that are not in the tree one after the other, as `insert`, so every
+    /// witness is taken against the root left by the previous insertion. Nothing is inserted if
+    /// one of them is already in the tree, repeated, or does not fit.
+    pub fn insert_sequential(
+        &mut self,
+        values: &[F],
+    ) -> Result<Vec<IndexedInsertion<F>>, PoseidonError> {
+        let mut keys = BTreeSet::new();
+        for value in values {
+            let key = field_to_bytes_be(value);
+            if self.indices.contains_key(&key) || !keys.insert(key) {
+                return Err(PoseidonError::KeyExists);
+            }
+        }
+        if self.leaves.len() + values.len() > self.tree.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        values.iter().map(|value| self.insert(*value)).collect()
+    }
+
+    /// Returns the index of the leaf whose value is below the given one and whose next value is
+    /// above it.
+    fn low_leaf_index(&self, value: F) -> Result<usize, PoseidonError> {
+        let key = field_to_bytes_be(&value);
+        if self.indices.contains_key(&key) {
+            return Err(PoseidonError::KeyExists);
+        }
+
+        let (_, index) = self
+            .indices
+            .range(..key)
+            .next_back()
+            .expect("Invalid parameters!");
+        Ok(*index)
+    }
+}
+
+/// Returns the leaf index given by the path indices of a proof, or `None` if they are not bits
+/// or do not fit in an index.
+fn leaf_index<F>(proof: &MerkleProof<F>) -> Option<usize> {
+    if proof.path_indices.len() >= usize::BITS as usize
+        || proof.path_indices.iter().any(|index| *index > 1)
+    {
+        return None;
+    }
+
+    Some(
+        proof
+            .path_indices
+            .iter()
+            .enumerate()
+            .map(|(level, index)| index << level)
+            .sum(),
+    )
+}
+
+impl<F: PoseidonField> IndexedProof<F> {
+    /// Checks that the value is not in the tree of the proof's root.
+    pub fn verify(&self, value: F) -> bool {
+        self.low_leaf.is_low_leaf(value)
+            && self.proof.leaf == self.low_leaf.hash()
+            && leaf_index(&self.proof) == Some(self.low_leaf_index)
+            && self.proof.verify()
+    }
+}
+
+impl<F: PoseidonField> IndexedInsertion<F> {
+    /// Returns the root before the insertion.
+    pub fn old_root(&self) -> F {
+        self.low_leaf_proof.root
+    }
+
+    /// Returns the root after the insertion.
+    pub fn new_root(&self) -> F {
+        self.new_leaf_proof.root
+    }
+
+    /// Checks that the insertion leads from the old root to the new one: the low leaf is in the
+    /// old tree, and pointing it to the new leaf in an empty slot gives the new tree.
+    pub fn verify(&self) -> bool {
+        let low_leaf_proof = IndexedProof {
+            low_leaf: self.low_leaf,
+            low_leaf_index: self.low_leaf_index,
+            proof: self.low_leaf_proof.clone(),
+        };
+        if !low_leaf_proof.verify(self.new_leaf.value)
+            || self.new_leaf.next_index != self.low_leaf.next_index
+            || self.new_leaf.next_value != self.low_leaf.next_value
+            || leaf_index(&self.new_leaf_proof) != Some(self.new_leaf_index)
+        {
+            return false;
+        }
+
+        // Root once the low leaf points to the new leaf.
+        let updated = IndexedLeaf {
+            next_index: self.new_leaf_index,
+            next_value: self.new_leaf.value,
+            ..self.low_leaf
+        };
+        let mut proof = self.low_leaf_proof.clone();
+        proof.leaf = updated.hash();
+        let root = proof.compute_root();
+
+        let mut empty = self.new_leaf_proof.clone();
+        empty.leaf = F::ZERO;
+
+        root.is_some()
+            && empty.compute_root() == root
+            && self.new_leaf_proof.leaf == self.new_leaf.hash()
+            && self.new_leaf_proof.verify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_indexed_tree() {
+        let mut tree = IndexedMerkleTree::new(3).unwrap();
+        for value in [30, 10, 20, 50] {
+            tree.insert(Fr::from(value)).unwrap();
+        }
+
+        // The leaves form a sorted linked list.
+        let expected = [
+            (0, 2, 10),
+            (30, 4, 50),
+            (10, 3, 20),
+            (20, 1, 30),
+            (50, 0, 0),
+        ];
+        let leaves: Vec<_> = tree
+            .leaves()
+            .iter()
+            .map(|leaf| (leaf.value, leaf.next_index, leaf.next_value))
+            .collect();
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(value, index, next)| (Fr::from(*value), *index, Fr::from(*next)))
+            .collect();
+        assert_eq!(leaves, expected);
+
+        // The root is the binary tree of the leaf hashes.
+        let hashes: Vec<Fr> = tree.leaves().iter().map(IndexedLeaf::hash).collect();
+        let expected = MerkleTree::with_leaves(3, Fr::zero(), &hashes).unwrap();
+        assert_eq!(tree.root(), expected.root());
+        assert!(tree.contains(Fr::from(20)) && !tree.contains(Fr::from(25)));
+    }
+
+    #[test]
+    fn test_indexed_non_membership() {
+        let mut tree = IndexedMerkleTree::new(3).unwrap();
+        tree.insert_sequential(&[10, 20, 30].map(Fr::from)).unwrap();
+
+        for (value, low) in [(5, 0), (15, 1), (25, 2), (100, 3)] {
+            let proof = tree.non_membership_proof(Fr::from(value)).unwrap();
+            assert_eq!(proof.low_leaf_index, low);
+            assert!(proof.verify(Fr::from(value)));
+        }
+
+        let proof = tree.non_membership_proof(Fr::from(15)).unwrap();
+        assert!(!proof.verify(Fr::from(20)));
+        assert!(!proof.verify(Fr::from(25)));
+
+        // Path indices other than bits, or too many of them, are rejected instead of shifted.
+        let mut wrong = proof.clone();
+        wrong.proof.path_indices[0] = 3;
+        assert!(!wrong.verify(Fr::from(15)));
+
+        let mut wrong = proof.clone();
+        wrong.proof.path_indices = vec![0; 65];
+        wrong.proof.path_indices[64] = 1;
+        wrong.proof.siblings = vec![Fr::zero(); 65];
+        assert!(!wrong.verify(Fr::from(15)));
+        assert_eq!(
+            tree.non_membership_proof(Fr::from(20)),
+            Err(PoseidonError::KeyExists)
+        );
+    }
+
+    #[test]
+    fn test_indexed_insertion() {
+        let mut tree = IndexedMerkleTree::new(4).unwrap();
+        let mut root = tree.root();
+
+        let values = [7, 3, 11, 5, 1 << 40].map(Fr::from);
+        for insertion in tree.insert_sequential(&values).unwrap() {
+            assert_eq!(insertion.old_root(), root);
+            assert!(insertion.verify());
+            root = insertion.new_root();
+        }
+        assert_eq!(tree.root(), root);
+
+        let mut wrong = tree.insert(Fr::from(4)).unwrap();
+        wrong.new_leaf.next_value = Fr::from(6);
+        assert!(!wrong.verify());
+
+        let mut wrong = tree.insert(Fr::from(9)).unwrap();
+        wrong.new_leaf_index += 1;
+        assert!(!wrong.verify());
+    }
+
+    #[test]
+    fn test_indexed_errors() {
+        let mut tree = IndexedMerkleTree::new(2).unwrap();
+        assert_eq!(tree.insert(Fr::zero()), Err(PoseidonError::KeyExists));
+
+        tree.insert(Fr::from(1)).unwrap();
+        let before = tree.clone();
+        assert_eq!(
+            tree.insert_sequential(&[2, 3, 2].map(Fr::from)),
+            Err(PoseidonError::KeyExists)
+        );
+        assert_eq!(
+            tree.insert_sequential(&[2, 3, 4].map(Fr::from)),
+            Err(PoseidonError::TreeFull)
+        );
+        assert_eq!(tree, before);
+
+        tree.insert_sequential(&[2, 3].map(Fr::from)).unwrap();
+        assert_eq!(tree.insert(Fr::from(4)), Err(PoseidonError::TreeFull));
+    }
+}
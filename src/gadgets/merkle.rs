@@ -0,0 +1,416 @@
+use super::*;
+use batch::hash_batch;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use params::PoseidonField;
+use templates::permute;
+
+/// Maximum depth of a `MerkleTree`, as zk-kit's IMT.
+pub(crate) const MAX_DEPTH: usize = 32;
+
+/// Returns Poseidon(left, right), the node hash of binary trees.
+pub(crate) fn hash_pair<F: PoseidonField>(left: F, right: F) -> F {
+    let mut state = [F::ZERO, left, right];
+    permute(&mut state).expect("Invalid parameters!");
+    state[0]
+}
+
+/// Returns the zero of every level up to the given depth: `zeros[i] = Poseidon(zeros[i - 1],
+/// zeros[i - 1])` starting from the empty leaf.
+pub(crate) fn zeros<F: PoseidonField>(zero: F, depth: usize) -> Vec<F> {
+    let mut zeros = vec![zero];
+    for level in 0..depth {
+        zeros.push(hash_pair(zeros[level], zeros[level]));
+    }
+    zeros
+}
+
+/// Nodes of a fixed-depth tree of any arity, as read and written by the path helpers shared by
+/// the trees. Levels count from 0 for the leaves.
+pub(crate) trait TreeNodes<F> {
+    /// Returns the number of children of every node.
+    fn arity(&self) -> usize;
+
+    /// Returns the depth of the tree.
+    fn depth(&self) -> usize;
+
+    /// Returns the node at the level and position, the zero of the level if empty.
+    fn get_node(&self, level: usize, position: usize) -> Result<F, PoseidonError>;
+
+    /// Sets the node at the level and position.
+    fn put_node(&mut self, level: usize, position: usize, node: F) -> Result<(), PoseidonError>;
+
+    /// Returns the hash of the children of a node.
+    fn hash_children(&self, children: &[F]) -> F;
+}
+
+/// Returns the children of the parent of the node at the level and position.
+pub(crate) fn children<F, T: TreeNodes<F>>(
+    tree: &T,
+    level: usize,
+    position: usize,
+) -> Result<Vec<F>, PoseidonError> {
+    let first = position - position % tree.arity();
+    (first..first + tree.arity())
+        .map(|position| tree.get_node(level, position))
+        .collect()
+}
+
+/// Sets the leaf at the index, rehashes its path and returns the new root.
+pub(crate) fn set_path<F: Copy, T: TreeNodes<F>>(
+    tree: &mut T,
+    index: usize,
+    leaf: F,
+) -> Result<F, PoseidonError> {
+    let mut node = leaf;
+    let mut position = index;
+    for level in 0..tree.depth() {
+        tree.put_node(level, position, node)?;
+        node = tree.hash_children(&children(tree, level, position)?);
+        position /= tree.arity();
+    }
+
+    tree.put_node(tree.depth(), 0, node)?;
+    Ok(node)
+}
+
+/// Returns, for every level, the other children of the parent of the node on the path of the
+/// leaf at the index and the position of the node among them.
+pub(crate) fn path<F, T: TreeNodes<F>>(
+    tree: &T,
+    index: usize,
+) -> Result<(Vec<Vec<F>>, Vec<usize>), PoseidonError> {
+    let mut siblings = Vec::with_capacity(tree.depth());
+    let mut path_indices = Vec::with_capacity(tree.depth());
+    let mut position = index;
+    for level in 0..tree.depth() {
+        let mut children = children(tree, level, position)?;
+        children.remove(position % tree.arity());
+        siblings.push(children);
+        path_indices.push(position % tree.arity());
+        position /= tree.arity();
+    }
+    Ok((siblings, path_indices))
+}
+
+/// Checks that the index is one of the first `len` leaves.
+pub(crate) fn check_index(index: usize, len: usize) -> Result<(), PoseidonError> {
+    if index >= len {
+        return Err(PoseidonError::IndexOutOfRange { index, len });
+    }
+    Ok(())
+}
+
+/// Sets the node at the position of a level filled from the left, appending it at the end.
+pub(crate) fn put_level<F>(nodes: &mut Vec<F>, position: usize, node: F) {
+    if position == nodes.len() {
+        nodes.push(node);
+    } else {
+        nodes[position] = node;
+    }
+}
+
+/// Fixed-depth binary Merkle tree hashed with Poseidon(left, right), as zk-kit's IMT with
+/// arity 2. Leaves are filled from the left and empty subtrees hash to the zero of their level.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree<F = Fr> {
+    depth: usize,
+    zeros: Vec<F>,
+    nodes: Vec<Vec<F>>,
+}
+
+/// Inclusion proof of a leaf, as zk-kit's `MerkleProof`. `siblings` and `path_indices` are
+/// the `pathElements` and `pathIndices` inputs of circomlib's MerkleTreeChecker: index 0
+/// means the node is the left child.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof<F = Fr> {
+    pub root: F,
+    pub leaf: F,
+    pub siblings: Vec<F>,
+    pub path_indices: Vec<usize>,
+}
+
+impl<F: PoseidonField> MerkleTree<F> {
+    /// Creates an empty tree of the given depth whose empty leaves are `zero`.
+    pub fn new(depth: usize, zero: F) -> Result<Self, PoseidonError> {
+        if !(1..=MAX_DEPTH).contains(&depth) {
+            return Err(PoseidonError::InvalidDepth(depth));
+        }
+
+        Ok(Self {
+            depth,
+            zeros: zeros(zero, depth),
+            nodes: vec![Vec::new(); depth + 1],
+        })
+    }
+
+    /// Creates a tree of the given depth holding the given leaves, hashing level by level.
+    pub fn with_leaves(depth: usize, zero: F, leaves: &[F]) -> Result<Self, PoseidonError> {
+        let mut tree = Self::new(depth, zero)?;
+        if leaves.len() > tree.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        tree.nodes[0] = leaves.to_vec();
+        for level in 0..depth {
+            let pairs: Vec<[F; 2]> = tree.nodes[level]
+                .chunks(2)
+                .map(|pair| [pair[0], pair.get(1).copied().unwrap_or(tree.zeros[level])])
+                .collect();
+            tree.nodes[level + 1] = hash_batch(&pairs).expect("Invalid parameters!");
+        }
+
+        Ok(tree)
+    }
+
+    /// Returns the depth of the tree.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the maximum number of leaves.
+    pub fn capacity(&self) -> usize {
+        1 << self.depth
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.node(self.depth, 0)
+    }
+
+    /// Returns the inserted leaves.
+    pub fn leaves(&self) -> &[F] {
+        &self.nodes[0]
+    }
+
+    /// Returns the zero of every level, from the empty leaf to the empty root.
+    pub fn zeros(&self) -> &[F] {
+        &self.zeros
+    }
+
+    /// Returns the index of the first leaf equal to the given one.
+    pub fn index_of(&self, leaf: F) -> Option<usize> {
+        self.nodes[0].iter().position(|item| *item == leaf)
+    }
+
+    /// Appends a leaf and returns its index.
+    pub fn insert(&mut self, leaf: F) -> Result<usize, PoseidonError> {
+        let index = self.nodes[0].len();
+        if index == self.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        set_path(self, index, leaf)?;
+        Ok(index)
+    }
+
+    /// Replaces the leaf at the given index.
+    pub fn update(&mut self, index: usize, leaf: F) -> Result<(), PoseidonError> {
+        check_index(index, self.nodes[0].len())?;
+        set_path(self, index, leaf)?;
+        Ok(())
+    }
+
+    /// Replaces the leaf at the given index with the zero leaf, keeping the other indices.
+    pub fn delete(&mut self, index: usize) -> Result<(), PoseidonError> {
+        self.update(index, self.zeros[0])
+    }
+
+    /// Returns the inclusion proof of the leaf at the given index.
+    pub fn proof(&self, index: usize) -> Result<MerkleProof<F>, PoseidonError> {
+        check_index(index, self.nodes[0].len())?;
+        let (siblings, path_indices) = path(self, index)?;
+
+        Ok(MerkleProof {
+            root: self.root(),
+            leaf: self.nodes[0][index],
+            siblings: siblings.concat(),
+            path_indices,
+        })
+    }
+
+    /// Removes the last leaf, as if it was never inserted.
+    pub(crate) fn pop(&mut self) -> Option<F> {
+        let index = self.nodes[0].len().checked_sub(1)?;
+        let leaf = self.nodes[0][index];
+
+        // Nodes holding only empty leaves are the zeros of their level, so they can be dropped.
+        set_path(self, index, self.zeros[0]).expect("Invalid parameters!");
+        for level in 0..=self.depth {
+            let len = (index + (1 << level) - 1) >> level;
+            self.nodes[level].truncate(len);
+        }
+
+        Some(leaf)
+    }
+
+    /// Returns the node at the given level and position, the zero of the level if empty.
+    pub(crate) fn node(&self, level: usize, position: usize) -> F {
+        let nodes = &self.nodes[level];
+        nodes.get(position).copied().unwrap_or(self.zeros[level])
+    }
+}
+
+impl<F: PoseidonField> TreeNodes<F> for MerkleTree<F> {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn get_node(&self, level: usize, position: usize) -> Result<F, PoseidonError> {
+        Ok(self.node(level, position))
+    }
+
+    fn put_node(&mut self, level: usize, position: usize, node: F) -> Result<(), PoseidonError> {
+        put_level(&mut self.nodes[level], position, node);
+        Ok(())
+    }
+
+    fn hash_children(&self, children: &[F]) -> F {
+        hash_pair(children[0], children[1])
+    }
+}
+
+impl<F: PoseidonField> MerkleProof<F> {
+    /// Returns the root obtained by hashing the leaf up with the siblings, or None if the
+    /// path is malformed.
+    pub fn compute_root(&self) -> Option<F> {
+        if self.siblings.len() != self.path_indices.len() {
+            return None;
+        }
+
+        let mut node = self.leaf;
+        for (sibling, index) in self.siblings.iter().zip(&self.path_indices) {
+            node = match index {
+                0 => hash_pair(node, *sibling),
+                1 => hash_pair(*sibling, node),
+                _ => return None,
+            };
+        }
+
+        Some(node)
+    }
+
+    /// Checks that the proof leads from the leaf to the root.
+    pub fn verify(&self) -> bool {
+        self.compute_root() == Some(self.root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use utils::dec_to_field;
+
+    #[test]
+    fn test_merkle_zeros() {
+        // Zeros of the Poseidon trees with zero leaves, as in circomlibjs.
+        let expected = [
+            "0",
+            "14744269619966411208579211824598458697587494354926760081771325075741142829156",
+            "7423237065226347324353380772367382631490014989348495481811164164159255474657",
+            "11286972368698509976183087595462810875513684078608517520839298933882497716792",
+        ]
+        .map(|item| dec_to_field::<Fr>(item).unwrap());
+
+        let tree = MerkleTree::new(3, Fr::zero()).unwrap();
+        assert_eq!(tree.zeros(), expected);
+        assert_eq!(tree.root(), expected[3]);
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        let leaves: Vec<Fr> = (1..=5).map(Fr::from).collect();
+        let zero = Fr::from(7);
+
+        // Root of a depth 3 tree, hashed by hand.
+        let level_1 = [
+            hash_pair(leaves[0], leaves[1]),
+            hash_pair(leaves[2], leaves[3]),
+            hash_pair(leaves[4], zero),
+            hash_pair(zero, zero),
+        ];
+        let level_2 = [
+            hash_pair(level_1[0], level_1[1]),
+            hash_pair(level_1[2], level_1[3]),
+        ];
+        let expected = hash_pair(level_2[0], level_2[1]);
+
+        let mut tree = MerkleTree::new(3, zero).unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(tree.insert(*leaf), Ok(i));
+        }
+        assert_eq!(tree.root(), expected);
+        assert_eq!(tree, MerkleTree::with_leaves(3, zero, &leaves).unwrap());
+        assert_eq!(tree.index_of(leaves[3]), Some(3));
+
+        // Updating and deleting match a tree built from the resulting leaves.
+        tree.update(1, Fr::from(9)).unwrap();
+        tree.delete(4).unwrap();
+        let leaves = [1, 9, 3, 4].map(Fr::from).into_iter().chain([zero]);
+        let other = MerkleTree::with_leaves(3, zero, &leaves.collect::<Vec<_>>()).unwrap();
+        assert_eq!(tree.root(), other.root());
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let leaves: Vec<Fr> = (0..11).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(4, Fr::zero(), &leaves).unwrap();
+
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = tree.proof(i).unwrap();
+            assert_eq!(proof.leaf, *leaf);
+            assert_eq!(proof.root, tree.root());
+            assert_eq!(
+                proof.path_indices,
+                (0..4).map(|j| (i >> j) & 1).collect::<Vec<_>>()
+            );
+            assert!(proof.verify());
+        }
+
+        let proof = tree.proof(5).unwrap();
+        let mut wrong = proof.clone();
+        wrong.leaf = Fr::from(6);
+        assert!(!wrong.verify());
+
+        let mut wrong = proof.clone();
+        wrong.path_indices[2] = 2;
+        assert!(!wrong.verify());
+
+        let mut wrong = proof;
+        wrong.siblings.pop();
+        assert_eq!(wrong.compute_root(), None);
+    }
+
+    #[test]
+    fn test_merkle_errors() {
+        assert_eq!(
+            MerkleTree::new(0, Fr::zero()),
+            Err(PoseidonError::InvalidDepth(0))
+        );
+        assert_eq!(
+            MerkleTree::new(33, Fr::zero()),
+            Err(PoseidonError::InvalidDepth(33))
+        );
+        assert_eq!(
+            MerkleTree::with_leaves(1, Fr::zero(), &[Fr::one(); 3]),
+            Err(PoseidonError::TreeFull)
+        );
+
+        let mut tree = MerkleTree::new(1, Fr::zero()).unwrap();
+        tree.insert(Fr::one()).unwrap();
+        tree.insert(Fr::one()).unwrap();
+        assert_eq!(tree.insert(Fr::one()), Err(PoseidonError::TreeFull));
+        assert_eq!(
+            tree.update(2, Fr::one()),
+            Err(PoseidonError::IndexOutOfRange { index: 2, len: 2 })
+        );
+        assert_eq!(
+            tree.proof(2),
+            Err(PoseidonError::IndexOutOfRange { index: 2, len: 2 })
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use super::*;
+use chip::{PoseidonChip, PoseidonConfig};
+use halo2::{
+    circuit::{AssignedCell, Layouter},
+    halo2curves::bn256::Fr,
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Selector},
+    poly::Rotation,
+};
+use params::PoseidonField;
+
+/// Columns and selector of the Merkle path chip, with the Poseidon chip for t = 3.
+#[derive(Clone, Debug)]
+pub struct MerklePathConfig {
+    poseidon: PoseidonConfig,
+    bit: Column<Advice>,
+    node: Column<Advice>,
+    sibling: Column<Advice>,
+    left: Column<Advice>,
+    right: Column<Advice>,
+    swap: Selector,
+}
+
+/// Lays out circomlib's MerkleTreeInclusionProof in-circuit: every level orders the node and
+/// its sibling by the path bit, as DualMux, and hashes them with Poseidon(left, right), so the
+/// root matches `MerkleTree` and the circom circuits.
+#[derive(Clone, Debug)]
+pub struct MerklePathChip<F = Fr> {
+    config: MerklePathConfig,
+    poseidon: PoseidonChip<F>,
+}
+
+impl<F: PoseidonField> MerklePathChip<F> {
+    pub fn construct(config: MerklePathConfig) -> Self {
+        Self {
+            poseidon: PoseidonChip::construct(config.poseidon.clone()),
+            config,
+        }
+    }
+
+    /// Configures the swap gate and the Poseidon gates for hashing pairs.
+    pub fn configure(meta: &mut ConstraintSystem<F>) -> MerklePathConfig {
+        let poseidon = PoseidonChip::configure(meta, 3);
+        let [bit, node, sibling, left, right] = [(); 5].map(|_| meta.advice_column());
+        let constant = meta.fixed_column();
+        let swap = meta.selector();
+
+        for column in [bit, node, sibling, left, right] {
+            meta.enable_equality(column);
+        }
+        meta.enable_constant(constant);
+
+        // Bit 0 keeps the node on the left, bit 1 swaps it with the sibling.
+        meta.create_gate("merkle path swap", |meta| {
+            let selector = meta.query_selector(swap);
+            let bit = meta.query_advice(bit, Rotation::cur());
+            let node = meta.query_advice(node, Rotation::cur());
+            let sibling = meta.query_advice(sibling, Rotation::cur());
+            let left = meta.query_advice(left, Rotation::cur());
+            let right = meta.query_advice(right, Rotation::cur());
+
+            let one = Expression::Constant(F::ONE);
+            vec![
+                selector.clone() * bit.clone() * (one - bit.clone()),
+                selector.clone()
+                    * (left - (node.clone() + bit.clone() * (sibling.clone() - node.clone()))),
+                selector * (right - (sibling.clone() + bit * (node - sibling))),
+            ]
+        });
+
+        MerklePathConfig {
+            poseidon,
+            bit,
+            node,
+            sibling,
+            left,
+            right,
+            swap,
+        }
+    }
+
+    /// Hashes the leaf up with the siblings, the path bits being the `pathIndices` of
+    /// MerkleTreeInclusionProof, and returns the root. The bits are constrained to be boolean.
+    pub fn compute_root(
+        &self,
+        mut layouter: impl Layouter<F>,
+        leaf: &AssignedCell<F, F>,
+        path_bits: &[AssignedCell<F, F>],
+        siblings: &[AssignedCell<F, F>],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let config = &self.config;
+        assert_eq!(path_bits.len(), siblings.len(), "Invalid parameters!");
+
+        let zero = layouter.assign_region(
+            || "zero",
+            |mut region| region.assign_advice_from_constant(|| "zero", config.bit, 0, F::ZERO),
+        )?;
+
+        let mut node = leaf.clone();
+        for (level, (bit, sibling)) in path_bits.iter().zip(siblings).enumerate() {
+            let (left, right) = layouter.assign_region(
+                || "merkle path swap",
+                |mut region| {
+                    config.swap.enable(&mut region, 0)?;
+                    let bit = bit.copy_advice(|| "bit", &mut region, config.bit, 0)?;
+                    let node = node.copy_advice(|| "node", &mut region, config.node, 0)?;
+                    let sibling =
+                        sibling.copy_advice(|| "sibling", &mut region, config.sibling, 0)?;
+
+                    let values = bit.value().zip(node.value()).zip(sibling.value()).map(
+                        |((bit, node), sibling)| {
+                            (
+                                *node + *bit * (*sibling - node),
+                                *sibling + *bit * (*node - sibling),
+                            )
+                        },
+                    );
+                    let left = region.assign_advice(
+                        || "left",
+                        config.left,
+                        0,
+                        || values.map(|(left, _)| left),
+                    )?;
+                    let right = region.assign_advice(
+                        || "right",
+                        config.right,
+                        0,
+                        || values.map(|(_, right)| right),
+                    )?;
+                    Ok((left, right))
+                },
+            )?;
+
+            let outputs = self.poseidon.poseidon_ex(
+                layouter.namespace(|| format!("merkle path level {}", level)),
+                &[left, right],
+                &zero,
+                1,
+            )?;
+            node = outputs[0].clone();
+        }
+
+        Ok(node)
+    }
+
+    /// Constrains the root computed from the path to be equal to the given one.
+    pub fn constrain_root(
+        &self,
+        mut layouter: impl Layouter<F>,
+        leaf: &AssignedCell<F, F>,
+        path_bits: &[AssignedCell<F, F>],
+        siblings: &[AssignedCell<F, F>],
+        root: &AssignedCell<F, F>,
+    ) -> Result<(), Error> {
+        let computed =
+            self.compute_root(layouter.namespace(|| "path"), leaf, path_bits, siblings)?;
+        layouter.assign_region(
+            || "merkle root",
+            |mut region| region.constrain_equal(computed.cell(), root.cell()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use halo2::{
+        circuit::{SimpleFloorPlanner, Value},
+        dev::MockProver,
+        plonk::{Circuit, Instance},
+    };
+    use merkle::MerkleTree;
+
+    #[derive(Clone, Debug)]
+    struct TestConfig {
+        merkle: MerklePathConfig,
+        instance: Column<Instance>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuit {
+        leaf: Value<Fr>,
+        path_bits: Vec<Value<Fr>>,
+        siblings: Vec<Value<Fr>>,
+        root: Value<Fr>,
+    }
+
+    impl Circuit<Fr> for TestCircuit {
+        type Config = TestConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            Self {
+                leaf: Value::unknown(),
+                path_bits: vec![Value::unknown(); self.path_bits.len()],
+                siblings: vec![Value::unknown(); self.siblings.len()],
+                root: Value::unknown(),
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<Fr>) -> TestConfig {
+            let merkle = MerklePathChip::configure(meta);
+            let instance = meta.instance_column();
+            meta.enable_equality(instance);
+
+            TestConfig { merkle, instance }
+        }
+
+        fn synthesize(
+            &self,
+            config: TestConfig,
+            mut layouter: impl Layouter<Fr>,
+        ) -> Result<(), Error> {
+            let (leaf, path_bits, siblings, root) = layouter.assign_region(
+                || "load path",
+                |mut region| {
+                    let merkle = &config.merkle;
+                    let leaf = region.assign_advice(|| "leaf", merkle.node, 0, || self.leaf)?;
+                    let root = region.assign_advice(|| "root", merkle.left, 0, || self.root)?;
+                    let path_bits = self
+                        .path_bits
+                        .iter()
+                        .enumerate()
+                        .map(|(i, item)| region.assign_advice(|| "bit", merkle.bit, i, || *item))
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    let siblings = self
+                        .siblings
+                        .iter()
+                        .enumerate()
+                        .map(|(i, item)| {
+                            region.assign_advice(|| "sibling", merkle.sibling, i, || *item)
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    Ok((leaf, path_bits, siblings, root))
+                },
+            )?;
+
+            let chip = MerklePathChip::construct(config.merkle);
+            let computed = chip.compute_root(
+                layouter.namespace(|| "merkle path"),
+                &leaf,
+                &path_bits,
+                &siblings,
+            )?;
+            layouter.constrain_instance(computed.cell(), config.instance, 0)?;
+
+            chip.constrain_root(
+                layouter.namespace(|| "merkle root"),
+                &leaf,
+                &path_bits,
+                &siblings,
+                &root,
+            )
+        }
+    }
+
+    fn circuit(leaf: Fr, path_bits: &[Fr], siblings: &[Fr], root: Fr) -> TestCircuit {
+        TestCircuit {
+            leaf: Value::known(leaf),
+            path_bits: path_bits.iter().map(|item| Value::known(*item)).collect(),
+            siblings: siblings.iter().map(|item| Value::known(*item)).collect(),
+            root: Value::known(root),
+        }
+    }
+
+    fn proof_circuit(tree: &MerkleTree, index: usize) -> TestCircuit {
+        let proof = tree.proof(index).unwrap();
+        let path_bits: Vec<Fr> = proof
+            .path_indices
+            .iter()
+            .map(|item| Fr::from(*item as u64))
+            .collect();
+        circuit(proof.leaf, &path_bits, &proof.siblings, proof.root)
+    }
+
+    #[test]
+    fn test_merkle_path_chip() {
+        let leaves: Vec<Fr> = (0..13).map(|i| Fr::from(i * 7 + 1)).collect();
+        let tree = MerkleTree::with_leaves(4, Fr::zero(), &leaves).unwrap();
+        let k = 11;
+
+        for index in [0, 5, 12] {
+            let circuit = proof_circuit(&tree, index);
+            let prover = MockProver::run(k, &circuit, vec![vec![tree.root()]]).unwrap();
+            assert_eq!(prover.verify(), Ok(()));
+
+            let prover = MockProver::run(k, &circuit, vec![vec![Fr::one()]]).unwrap();
+            assert!(prover.verify().is_err());
+        }
+
+        // A root other than the computed one is rejected.
+        let mut wrong = proof_circuit(&tree, 5);
+        wrong.root = Value::known(Fr::one());
+        let prover = MockProver::run(k, &wrong, vec![vec![tree.root()]]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // So is the path of another leaf.
+        let mut wrong = proof_circuit(&tree, 5);
+        wrong.leaf = Value::known(leaves[4]);
+        let prover = MockProver::run(k, &wrong, vec![vec![tree.root()]]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+
+    #[test]
+    fn test_merkle_path_chip_bits() {
+        let leaves: Vec<Fr> = (0..4).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(2, Fr::zero(), &leaves).unwrap();
+        let proof = tree.proof(1).unwrap();
+        let k = 10;
+
+        // Path bits other than 0 and 1 are rejected, even when the root is computed from them.
+        let bits = [Fr::from(2), Fr::zero()];
+        let [left, right] = [
+            proof.leaf + bits[0] * (proof.siblings[0] - proof.leaf),
+            proof.siblings[0] + bits[0] * (proof.leaf - proof.siblings[0]),
+        ];
+        let node = merkle::hash_pair(left, right);
+        let root = merkle::hash_pair(node, proof.siblings[1]);
+
+        let wrong = circuit(proof.leaf, &bits, &proof.siblings, root);
+        let prover = MockProver::run(k, &wrong, vec![vec![root]]).unwrap();
+        assert!(prover.verify().is_err());
+
+        let bits = [Fr::one(), Fr::zero()];
+        let circuit = circuit(proof.leaf, &bits, &proof.siblings, proof.root);
+        let prover = MockProver::run(k, &circuit, vec![vec![proof.root]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+    }
+}
@@ -0,0 +1,371 @@
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use merkle::hash_pair;
+use params::PoseidonField;
+use utils::{bytes_be_to_field, field_to_bytes_be};
+
+/// Size of the header of a serialized proof: the number of leaves and the leaf index.
+const HEADER_SIZE: usize = 16;
+
+/// Append-only Merkle Mountain Range hashed with Poseidon(left, right).
+///
+/// The leaves form a list of perfect binary trees of decreasing height, one for every bit set
+/// in the number of leaves. The root bags their roots, the peaks, from the right and commits
+/// to the number of leaves: `Poseidon(len, Poseidon(p0, Poseidon(p1, ... pn)))`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleMountainRange<F = Fr> {
+    nodes: Vec<Vec<F>>,
+}
+
+/// Inclusion proof of a leaf against the bagged root of a range of `len` leaves. `siblings`
+/// lead from the leaf to its peak, as in `MerkleProof`, and `peaks` are the other peaks from
+/// the left.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmrProof<F = Fr> {
+    pub root: F,
+    pub len: usize,
+    pub index: usize,
+    pub leaf: F,
+    pub siblings: Vec<F>,
+    pub peaks: Vec<F>,
+}
+
+/// Returns the position among the peaks and the height of the peak above the leaf at the
+/// given index, or None if the index is out of range.
+fn locate(len: usize, index: usize) -> Option<(usize, usize)> {
+    let mut first = 0;
+    let mut peak = 0;
+    for height in (0..usize::BITS as usize).rev() {
+        if len >> height & 1 == 1 {
+            first += 1 << height;
+            if index < first {
+                return Some((peak, height));
+            }
+            peak += 1;
+        }
+    }
+    None
+}
+
+/// Returns the root of a range with the given number of leaves and peaks.
+fn bag<F: PoseidonField>(len: usize, peaks: &[F]) -> F {
+    let peaks = peaks
+        .iter()
+        .rev()
+        .copied()
+        .reduce(|node, peak| hash_pair(peak, node))
+        .unwrap_or(F::ZERO);
+    hash_pair(F::from(len as u64), peaks)
+}
+
+impl<F: PoseidonField> MerkleMountainRange<F> {
+    /// Creates an empty range.
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Vec::new()],
+        }
+    }
+
+    /// Creates a range holding the given leaves.
+    pub fn with_leaves(leaves: &[F]) -> Self {
+        let mut mmr = Self::new();
+        for leaf in leaves {
+            mmr.append(*leaf);
+        }
+        mmr
+    }
+
+    /// Returns the number of leaves.
+    pub fn len(&self) -> usize {
+        self.nodes[0].len()
+    }
+
+    /// Checks whether the range has no leaves.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the appended leaves.
+    pub fn leaves(&self) -> &[F] {
+        &self.nodes[0]
+    }
+
+    /// Returns the peaks from the highest to the lowest.
+    pub fn peaks(&self) -> Vec<F> {
+        let len = self.len();
+        (0..self.nodes.len())
+            .rev()
+            .filter(|height| len >> height & 1 == 1)
+            .map(|height| self.nodes[height][(len >> height) - 1])
+            .collect()
+    }
+
+    /// Returns the bagged root.
+    pub fn root(&self) -> F {
+        bag(self.len(), &self.peaks())
+    }
+
+    /// Appends a leaf, merging the peaks of equal height, and returns its index.
+    pub fn append(&mut self, leaf: F) -> usize {
+        let index = self.len();
+        let mut node = leaf;
+        let mut height = 0;
+        loop {
+            if height == self.nodes.len() {
+                self.nodes.push(Vec::new());
+            }
+            let nodes = &mut self.nodes[height];
+            nodes.push(node);
+            if nodes.len() & 1 == 1 {
+                return index;
+            }
+
+            node = hash_pair(nodes[nodes.len() - 2], node);
+            height += 1;
+        }
+    }
+
+    /// Returns the inclusion proof of the leaf at the given index.
+    pub fn proof(&self, index: usize) -> Result<MmrProof<F>, PoseidonError> {
+        let len = self.len();
+        let (peak, height) =
+            locate(len, index).ok_or(PoseidonError::IndexOutOfRange { index, len })?;
+
+        // Peaks are aligned to their size, so positions inside them are the global ones.
+        let siblings = (0..height)
+            .map(|level| self.nodes[level][(index >> level) ^ 1])
+            .collect();
+        let mut peaks = self.peaks();
+        peaks.remove(peak);
+
+        Ok(MmrProof {
+            root: self.root(),
+            len,
+            index,
+            leaf: self.nodes[0][index],
+            siblings,
+            peaks,
+        })
+    }
+}
+
+impl<F: PoseidonField> Default for MerkleMountainRange<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: PoseidonField> MmrProof<F> {
+    /// Returns the path indices of the leaf inside its peak, as `MerkleProof::path_indices`.
+    pub fn path_indices(&self) -> Vec<usize> {
+        (0..self.siblings.len())
+            .map(|level| self.index >> level & 1)
+            .collect()
+    }
+
+    /// Returns the root obtained by hashing the leaf up to its peak and bagging the peaks, or
+    /// None if the proof is malformed.
+    pub fn compute_root(&self) -> Option<F> {
+        let (peak, height) = locate(self.len, self.index)?;
+        if self.siblings.len() != height || self.peaks.len() + 1 != self.len.count_ones() as usize {
+            return None;
+        }
+
+        let mut node = self.leaf;
+        for (sibling, index) in self.siblings.iter().zip(self.path_indices()) {
+            node = if index == 0 {
+                hash_pair(node, *sibling)
+            } else {
+                hash_pair(*sibling, node)
+            };
+        }
+
+        let mut peaks = self.peaks.clone();
+        peaks.insert(peak, node);
+        Some(bag(self.len, &peaks))
+    }
+
+    /// Checks that the proof leads from the leaf to the root.
+    pub fn verify(&self) -> bool {
+        self.compute_root() == Some(self.root)
+    }
+
+    /// Serializes the proof as the number of leaves and the index, 8 big-endian bytes each,
+    /// followed by the root, the leaf, the siblings and the peaks, 32 big-endian bytes each.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(HEADER_SIZE + 32 * (2 + self.siblings.len() + self.peaks.len()));
+        bytes.extend_from_slice(&(self.len as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.index as u64).to_be_bytes());
+        for item in [self.root, self.leaf]
+            .iter()
+            .chain(&self.siblings)
+            .chain(&self.peaks)
+        {
+            bytes.extend_from_slice(&field_to_bytes_be(item));
+        }
+        bytes
+    }
+
+    /// Parses a proof serialized by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PoseidonError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(PoseidonError::MalformedProof("missing header".to_string()));
+        }
+
+        let (header, items) = bytes.split_at(HEADER_SIZE);
+        let len = u64::from_be_bytes(header[..8].try_into().expect("Invalid parameters!"));
+        let index = u64::from_be_bytes(header[8..].try_into().expect("Invalid parameters!"));
+        let (len, index) = match (usize::try_from(len), usize::try_from(index)) {
+            (Ok(len), Ok(index)) => (len, index),
+            _ => return Err(PoseidonError::MalformedProof("size overflow".to_string())),
+        };
+        let (_, height) = locate(len, index).ok_or_else(|| {
+            PoseidonError::MalformedProof(format!("index {} of {} leaves", index, len))
+        })?;
+
+        let n_peaks = len.count_ones() as usize - 1;
+        if items.len() != 32 * (2 + height + n_peaks) {
+            return Err(PoseidonError::MalformedProof(format!(
+                "expected {} bytes, got {}",
+                HEADER_SIZE + 32 * (2 + height + n_peaks),
+                bytes.len()
+            )));
+        }
+
+        let items = items
+            .chunks(32)
+            .map(|item| bytes_be_to_field(item.try_into().expect("Invalid parameters!")))
+            .collect::<Result<Vec<F>, _>>()?;
+        Ok(Self {
+            root: items[0],
+            len,
+            index,
+            leaf: items[1],
+            siblings: items[2..2 + height].to_vec(),
+            peaks: items[2 + height..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use merkle::MerkleTree;
+
+    #[test]
+    fn test_mmr_root() {
+        let leaves: Vec<Fr> = (0..7).map(Fr::from).collect();
+        let mut mmr = MerkleMountainRange::new();
+        assert_eq!(mmr.root(), hash_pair(Fr::zero(), Fr::zero()));
+
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(mmr.append(*leaf), i);
+        }
+        assert_eq!(mmr, MerkleMountainRange::with_leaves(&leaves));
+        assert_eq!(mmr.len(), 7);
+
+        // Peaks of 7 leaves are the roots of trees of 4, 2 and 1 leaves.
+        let peaks = [
+            MerkleTree::with_leaves(2, Fr::zero(), &leaves[..4])
+                .unwrap()
+                .root(),
+            hash_pair(leaves[4], leaves[5]),
+            leaves[6],
+        ];
+        assert_eq!(mmr.peaks(), peaks);
+
+        let bagged = hash_pair(peaks[0], hash_pair(peaks[1], peaks[2]));
+        assert_eq!(mmr.root(), hash_pair(Fr::from(7), bagged));
+
+        // Appending merges the peaks into one tree.
+        mmr.append(Fr::from(7));
+        let leaves: Vec<Fr> = (0..8).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(3, Fr::zero(), &leaves).unwrap();
+        assert_eq!(mmr.peaks(), [tree.root()]);
+        assert_eq!(mmr.root(), hash_pair(Fr::from(8), tree.root()));
+    }
+
+    #[test]
+    fn test_mmr_proof() {
+        let mut mmr = MerkleMountainRange::new();
+        for i in 0..19 {
+            mmr.append(Fr::from(i * 3));
+            for index in 0..=i as usize {
+                let proof = mmr.proof(index).unwrap();
+                assert_eq!(proof.leaf, Fr::from(index as u64 * 3));
+                assert!(proof.verify());
+            }
+        }
+
+        // Inside its peak, the proof is the proof of a binary tree.
+        let leaves: Vec<Fr> = (0..16).map(|i| Fr::from(i * 3)).collect();
+        let tree = MerkleTree::with_leaves(4, Fr::zero(), &leaves).unwrap();
+        let proof = mmr.proof(11).unwrap();
+        let expected = tree.proof(11).unwrap();
+        assert_eq!(proof.siblings, expected.siblings);
+        assert_eq!(proof.path_indices(), expected.path_indices);
+        assert_eq!(proof.peaks, mmr.peaks()[1..]);
+
+        let mut wrong = proof.clone();
+        wrong.leaf = Fr::one();
+        assert!(!wrong.verify());
+
+        // Proofs commit to the number of leaves.
+        let mut wrong = proof.clone();
+        wrong.len = 18;
+        assert_eq!(wrong.compute_root(), None);
+
+        let mut wrong = mmr.proof(18).unwrap();
+        wrong.siblings.push(Fr::zero());
+        assert_eq!(wrong.compute_root(), None);
+
+        assert_eq!(
+            mmr.proof(19).unwrap_err(),
+            PoseidonError::IndexOutOfRange { index: 19, len: 19 }
+        );
+    }
+
+    #[test]
+    fn test_mmr_proof_bytes() {
+        let leaves: Vec<Fr> = (0..11).map(Fr::from).collect();
+        let mmr = MerkleMountainRange::with_leaves(&leaves);
+
+        for index in [0, 9, 10] {
+            let proof = mmr.proof(index).unwrap();
+            let bytes = proof.to_bytes();
+            assert_eq!(
+                bytes.len(),
+                HEADER_SIZE + 32 * (2 + proof.siblings.len() + proof.peaks.len())
+            );
+            assert_eq!(MmrProof::from_bytes(&bytes), Ok(proof));
+        }
+
+        let bytes = mmr.proof(4).unwrap().to_bytes();
+        assert!(matches!(
+            MmrProof::<Fr>::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(PoseidonError::MalformedProof(_))
+        ));
+        assert!(matches!(
+            MmrProof::<Fr>::from_bytes(&bytes[..8]),
+            Err(PoseidonError::MalformedProof(_))
+        ));
+
+        // The index must be below the number of leaves.
+        let mut wrong = bytes.clone();
+        wrong[15] = 11;
+        assert!(matches!(
+            MmrProof::<Fr>::from_bytes(&wrong),
+            Err(PoseidonError::MalformedProof(_))
+        ));
+
+        // Field elements must be canonical.
+        let mut wrong = bytes;
+        wrong[HEADER_SIZE..HEADER_SIZE + 32].fill(0xff);
+        assert!(matches!(
+            MmrProof::<Fr>::from_bytes(&wrong),
+            Err(PoseidonError::NonCanonical(_))
+        ));
+    }
+}
@@ -1,3 +1,22 @@
+pub mod batch;
+pub mod checkpoint;
+pub mod chip;
 pub mod constants;
+pub mod error;
+pub mod grain;
+pub mod hasher;
+pub mod incremental;
+pub mod indexed;
+pub mod merkle;
+pub mod merkle_chip;
+pub mod mmr;
+pub mod multiproof;
+pub mod nary;
+pub mod optimize;
+pub mod params;
+pub mod reference;
+pub mod smt;
+pub mod sponge;
+pub mod storage;
 pub mod templates;
 pub mod utils;
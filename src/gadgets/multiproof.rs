@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use merkle::{hash_pair, MerkleProof, MerkleTree};
+use params::PoseidonField;
+
+/// Inclusion proof of several leaves of a binary tree. Siblings shared by the paths or
+/// computed from the proven leaves are left out; the remaining ones are listed level by level,
+/// from the leaves up, in the order of the positions they pair with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleMultiproof<F = Fr> {
+    pub root: F,
+    pub depth: usize,
+    pub indices: Vec<usize>,
+    pub leaves: Vec<F>,
+    pub siblings: Vec<F>,
+}
+
+/// Walks the levels from the given sorted positions, calling `pair` with the position of
+/// every node and the position of its sibling if it is known, and returns the positions of the
+/// next level.
+fn next_level(positions: &[usize], mut pair: impl FnMut(usize, Option<usize>)) -> Vec<usize> {
+    let mut parents = Vec::with_capacity(positions.len());
+    let mut i = 0;
+    while i < positions.len() {
+        let position = positions[i];
+        if position & 1 == 0 && positions.get(i + 1) == Some(&(position + 1)) {
+            pair(position, Some(position + 1));
+            i += 1;
+        } else {
+            pair(position, None);
+        }
+
+        parents.push(position >> 1);
+        i += 1;
+    }
+    parents
+}
+
+impl<F: PoseidonField> MerkleTree<F> {
+    /// Returns the multiproof of the leaves at the given indices.
+    pub fn multiproof(&self, indices: &[usize]) -> Result<MerkleMultiproof<F>, PoseidonError> {
+        if indices.is_empty() {
+            return Err(PoseidonError::InvalidParameters);
+        }
+
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        let len = self.leaves().len();
+        if let Some(index) = indices.iter().find(|index| **index >= len) {
+            return Err(PoseidonError::IndexOutOfRange { index: *index, len });
+        }
+
+        let mut siblings = Vec::new();
+        let mut positions = indices.clone();
+        for level in 0..self.depth() {
+            positions = next_level(&positions, |position, sibling| {
+                if sibling.is_none() {
+                    siblings.push(self.node(level, position ^ 1));
+                }
+            });
+        }
+
+        Ok(MerkleMultiproof {
+            root: self.root(),
+            depth: self.depth(),
+            leaves: indices.iter().map(|index| self.leaves()[*index]).collect(),
+            indices,
+            siblings,
+        })
+    }
+}
+
+impl<F: PoseidonField> MerkleMultiproof<F> {
+    /// Returns the root obtained by hashing the leaves up with the siblings, every internal
+    /// node once, or None if the proof is malformed.
+    pub fn compute_root(&self) -> Option<F> {
+        let capacity = 1usize.checked_shl(self.depth as u32)?;
+        if self.indices.is_empty()
+            || self.indices.len() != self.leaves.len()
+            || self.indices.windows(2).any(|pair| pair[0] >= pair[1])
+            || *self.indices.last()? >= capacity
+        {
+            return None;
+        }
+
+        let mut nodes = self.leaves.clone();
+        let mut positions = self.indices.clone();
+        let mut siblings = self.siblings.iter();
+        for _ in 0..self.depth {
+            let mut parents = Vec::with_capacity(nodes.len());
+            let mut i = 0;
+            let mut malformed = false;
+            positions = next_level(&positions, |position, sibling| {
+                let node = nodes[i];
+                let other = match sibling {
+                    Some(_) => {
+                        i += 1;
+                        nodes[i]
+                    }
+                    None => match siblings.next() {
+                        Some(sibling) => *sibling,
+                        None => {
+                            malformed = true;
+                            F::ZERO
+                        }
+                    },
+                };
+
+                parents.push(if position & 1 == 0 {
+                    hash_pair(node, other)
+                } else {
+                    hash_pair(other, node)
+                });
+                i += 1;
+            });
+
+            if malformed {
+                return None;
+            }
+            nodes = parents;
+        }
+
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(nodes[0])
+    }
+
+    /// Checks that the proof leads from the leaves to the root.
+    pub fn verify(&self) -> bool {
+        self.compute_root() == Some(self.root)
+    }
+}
+
+/// Checks inclusion proofs against the same root, hashing every internal node shared by
+/// several paths once: a path stops as soon as it reaches a node of an already checked path.
+/// All the proofs must have the depth of the first one.
+pub fn verify_batch<F: PoseidonField>(proofs: &[MerkleProof<F>]) -> bool {
+    let (root, depth) = match proofs.first() {
+        Some(proof) => (proof.root, proof.siblings.len()),
+        None => return true,
+    };
+    if depth >= usize::BITS as usize {
+        return false;
+    }
+
+    let mut known = HashMap::new();
+    for proof in proofs {
+        if proof.root != root
+            || proof.siblings.len() != depth
+            || proof.path_indices.len() != depth
+            || proof.path_indices.iter().any(|index| *index > 1)
+        {
+            return false;
+        }
+
+        let mut node = proof.leaf;
+        let mut path = Vec::with_capacity(proof.siblings.len());
+        let mut position: usize = proof
+            .path_indices
+            .iter()
+            .enumerate()
+            .map(|(level, index)| index << level)
+            .sum();
+        let mut reached = None;
+
+        for (level, (sibling, index)) in proof.siblings.iter().zip(&proof.path_indices).enumerate()
+        {
+            node = if *index == 0 {
+                hash_pair(node, *sibling)
+            } else {
+                hash_pair(*sibling, node)
+            };
+            position >>= 1;
+
+            if let Some(other) = known.get(&(level + 1, position)) {
+                reached = Some(*other);
+                break;
+            }
+            path.push(((level + 1, position), node));
+        }
+
+        match reached {
+            Some(other) if other != node => return false,
+            None if node != root => return false,
+            _ => known.extend(path),
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_multiproof() {
+        let leaves: Vec<Fr> = (0..13).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(4, Fr::zero(), &leaves).unwrap();
+
+        for indices in [
+            vec![0],
+            vec![3, 4],
+            vec![12, 0, 5, 6, 5],
+            vec![1, 2, 9, 10, 11],
+        ] {
+            let proof = tree.multiproof(&indices).unwrap();
+            assert!(proof.verify());
+
+            // Shared siblings are left out.
+            assert!(proof.siblings.len() <= proof.indices.len() * 4);
+            for (index, leaf) in proof.indices.iter().zip(&proof.leaves) {
+                assert_eq!(*leaf, leaves[*index]);
+            }
+        }
+
+        // Sibling leaves share every sibling above them.
+        let proof = tree.multiproof(&[6, 7]).unwrap();
+        assert_eq!(proof.siblings.len(), 3);
+        assert_eq!(proof.siblings, tree.proof(6).unwrap().siblings[1..]);
+
+        // Proving every leaf only needs the empty nodes next to the last ones.
+        let all: Vec<usize> = (0..13).collect();
+        let proof = tree.multiproof(&all).unwrap();
+        assert_eq!(proof.siblings, [tree.zeros()[0], tree.zeros()[1]]);
+        assert!(proof.verify());
+    }
+
+    #[test]
+    fn test_multiproof_invalid() {
+        let leaves: Vec<Fr> = (0..8).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(3, Fr::zero(), &leaves).unwrap();
+        let proof = tree.multiproof(&[1, 5]).unwrap();
+
+        let mut wrong = proof.clone();
+        wrong.leaves[1] = Fr::from(6);
+        assert!(!wrong.verify());
+
+        let mut wrong = proof.clone();
+        wrong.indices = vec![5, 1];
+        assert_eq!(wrong.compute_root(), None);
+
+        let mut wrong = proof.clone();
+        wrong.siblings.pop();
+        assert_eq!(wrong.compute_root(), None);
+
+        let mut wrong = proof;
+        wrong.siblings.push(Fr::zero());
+        assert_eq!(wrong.compute_root(), None);
+
+        assert_eq!(
+            tree.multiproof(&[]).unwrap_err(),
+            PoseidonError::InvalidParameters
+        );
+        assert_eq!(
+            tree.multiproof(&[2, 8]).unwrap_err(),
+            PoseidonError::IndexOutOfRange { index: 8, len: 8 }
+        );
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let leaves: Vec<Fr> = (0..20).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(5, Fr::zero(), &leaves).unwrap();
+        let mut proofs: Vec<_> = [3, 4, 5, 19, 0, 4]
+            .iter()
+            .map(|index| tree.proof(*index).unwrap())
+            .collect();
+
+        assert!(verify_batch(&proofs));
+        assert!(verify_batch::<Fr>(&[]));
+
+        // A wrong leaf is caught where its path meets a checked one.
+        proofs[2].leaf = Fr::from(6);
+        assert!(!verify_batch(&proofs));
+
+        // So is a proof of another tree.
+        proofs[2] = MerkleTree::with_leaves(5, Fr::one(), &leaves)
+            .unwrap()
+            .proof(5)
+            .unwrap();
+        assert!(!verify_batch(&proofs));
+    }
+
+    #[test]
+    fn test_verify_batch_depth() {
+        let leaves: Vec<Fr> = (0..20).map(Fr::from).collect();
+        let tree = MerkleTree::with_leaves(5, Fr::zero(), &leaves).unwrap();
+        let mut proofs = vec![tree.proof(4).unwrap(), tree.proof(5).unwrap()];
+        assert!(verify_batch(&proofs));
+
+        // A proof of an internal node, cut to the levels above it, would reach a checked node.
+        let proof = &mut proofs[1];
+        proof.leaf = hash_pair(leaves[4], leaves[5]);
+        proof.siblings.remove(0);
+        proof.path_indices.remove(0);
+        assert!(proof.verify());
+        assert!(!verify_batch(&proofs));
+
+        // Paths too long for a position are rejected instead of shifted.
+        let mut proof = tree.proof(4).unwrap();
+        proof.siblings.resize(usize::BITS as usize, Fr::zero());
+        proof.path_indices.resize(usize::BITS as usize, 1);
+        assert!(!verify_batch(&[proof]));
+    }
+}
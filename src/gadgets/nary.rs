@@ -0,0 +1,350 @@
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use hasher::Hasher;
+use merkle::{check_index, path, put_level, set_path, TreeNodes, MAX_DEPTH};
+use params::PoseidonField;
+
+/// Fixed-depth Merkle tree whose nodes hash 2..=16 children with the circomlib Poseidon of
+/// the matching width, as MACI's `IncrementalQuinTree` and zk-kit's IMT. Leaves are filled
+/// from the left and empty subtrees hash to the zero of their level.
+#[derive(Clone, Debug)]
+pub struct NaryMerkleTree<F: 'static + Clone = Fr> {
+    hasher: Hasher<F>,
+    arity: usize,
+    depth: usize,
+    zeros: Vec<F>,
+    nodes: Vec<Vec<F>>,
+}
+
+/// Inclusion proof of a leaf, as MACI's `genMerklePath`: for every level, the other
+/// children of the node on the path and the position of the node among its siblings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NaryMerkleProof<F = Fr> {
+    pub root: F,
+    pub leaf: F,
+    pub siblings: Vec<Vec<F>>,
+    pub path_indices: Vec<usize>,
+}
+
+impl<F: PoseidonField> NaryMerkleTree<F> {
+    /// Creates an empty tree of the given depth and arity whose empty leaves are `zero`.
+    pub fn new(depth: usize, zero: F, arity: usize) -> Result<Self, PoseidonError> {
+        if !(2..=16).contains(&arity) {
+            return Err(PoseidonError::UnsupportedArity(arity));
+        }
+        if !(1..=MAX_DEPTH).contains(&depth) || arity.checked_pow(depth as u32).is_none() {
+            return Err(PoseidonError::InvalidDepth(depth));
+        }
+
+        let hasher = Hasher::new(arity)?;
+        let mut zeros = vec![zero];
+        for level in 0..depth {
+            let children = vec![zeros[level]; arity];
+            zeros.push(hasher.hash(&children)?);
+        }
+
+        Ok(Self {
+            hasher,
+            arity,
+            depth,
+            zeros,
+            nodes: vec![Vec::new(); depth + 1],
+        })
+    }
+
+    /// Creates a tree of the given depth and arity holding the given leaves, hashing level by
+    /// level.
+    pub fn with_leaves(
+        depth: usize,
+        zero: F,
+        arity: usize,
+        leaves: &[F],
+    ) -> Result<Self, PoseidonError> {
+        let mut tree = Self::new(depth, zero, arity)?;
+        if leaves.len() > tree.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        tree.nodes[0] = leaves.to_vec();
+        for level in 0..depth {
+            let mut parents = Vec::new();
+            for chunk in tree.nodes[level].chunks(arity) {
+                let mut children = chunk.to_vec();
+                children.resize(arity, tree.zeros[level]);
+                parents.push(tree.hasher.hash(&children)?);
+            }
+            tree.nodes[level + 1] = parents;
+        }
+
+        Ok(tree)
+    }
+
+    /// Returns the number of children of every node.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Returns the depth of the tree.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the maximum number of leaves.
+    pub fn capacity(&self) -> usize {
+        self.arity.pow(self.depth as u32)
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.node(self.depth, 0)
+    }
+
+    /// Returns the inserted leaves.
+    pub fn leaves(&self) -> &[F] {
+        &self.nodes[0]
+    }
+
+    /// Returns the zero of every level, from the empty leaf to the empty root.
+    pub fn zeros(&self) -> &[F] {
+        &self.zeros
+    }
+
+    /// Returns the index of the first leaf equal to the given one.
+    pub fn index_of(&self, leaf: F) -> Option<usize> {
+        self.nodes[0].iter().position(|item| *item == leaf)
+    }
+
+    /// Appends a leaf and returns its index.
+    pub fn insert(&mut self, leaf: F) -> Result<usize, PoseidonError> {
+        let index = self.nodes[0].len();
+        if index == self.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        set_path(self, index, leaf)?;
+        Ok(index)
+    }
+
+    /// Replaces the leaf at the given index.
+    pub fn update(&mut self, index: usize, leaf: F) -> Result<(), PoseidonError> {
+        check_index(index, self.nodes[0].len())?;
+        set_path(self, index, leaf)?;
+        Ok(())
+    }
+
+    /// Replaces the leaf at the given index with the zero leaf, keeping the other indices.
+    pub fn delete(&mut self, index: usize) -> Result<(), PoseidonError> {
+        self.update(index, self.zeros[0])
+    }
+
+    /// Returns the inclusion proof of the leaf at the given index.
+    pub fn proof(&self, index: usize) -> Result<NaryMerkleProof<F>, PoseidonError> {
+        check_index(index, self.nodes[0].len())?;
+        let (siblings, path_indices) = path(self, index)?;
+
+        Ok(NaryMerkleProof {
+            root: self.root(),
+            leaf: self.nodes[0][index],
+            siblings,
+            path_indices,
+        })
+    }
+
+    /// Returns the node at the given level and position, the zero of the level if empty.
+    fn node(&self, level: usize, position: usize) -> F {
+        let nodes = &self.nodes[level];
+        nodes.get(position).copied().unwrap_or(self.zeros[level])
+    }
+}
+
+impl<F: PoseidonField> TreeNodes<F> for NaryMerkleTree<F> {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn get_node(&self, level: usize, position: usize) -> Result<F, PoseidonError> {
+        Ok(self.node(level, position))
+    }
+
+    fn put_node(&mut self, level: usize, position: usize, node: F) -> Result<(), PoseidonError> {
+        put_level(&mut self.nodes[level], position, node);
+        Ok(())
+    }
+
+    fn hash_children(&self, children: &[F]) -> F {
+        self.hasher.hash(children).expect("Invalid parameters!")
+    }
+}
+
+impl<F: PoseidonField> NaryMerkleProof<F> {
+    /// Returns the root obtained by hashing the leaf up with the siblings, or None if the
+    /// path is malformed.
+    pub fn compute_root(&self) -> Option<F> {
+        if self.siblings.len() != self.path_indices.len() {
+            return None;
+        }
+
+        let arity = self
+            .siblings
+            .first()
+            .map_or(2, |siblings| siblings.len() + 1);
+        let hasher = Hasher::new(arity).ok()?;
+
+        let mut node = self.leaf;
+        for (siblings, index) in self.siblings.iter().zip(&self.path_indices) {
+            if siblings.len() + 1 != arity || *index >= arity {
+                return None;
+            }
+
+            let mut children = siblings.clone();
+            children.insert(*index, node);
+            node = hasher.hash(&children).ok()?;
+        }
+
+        Some(node)
+    }
+
+    /// Checks that the proof leads from the leaf to the root.
+    pub fn verify(&self) -> bool {
+        self.compute_root() == Some(self.root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hasher::hash;
+    use merkle::MerkleTree;
+    use utils::{dec_to_field, field_to_dec};
+
+    #[test]
+    fn test_nary_tree() {
+        let leaves: Vec<Fr> = (1..=7).map(Fr::from).collect();
+        let zero = Fr::from(9);
+
+        // Root of a depth 2 quinary tree, hashed by hand with Poseidon t = 6.
+        let zero_1 = hash(&[zero; 5]).unwrap();
+        let level_1 = [
+            hash(&leaves[..5]).unwrap(),
+            hash(&[leaves[5], leaves[6], zero, zero, zero]).unwrap(),
+        ];
+        let expected = hash(&[level_1[0], level_1[1], zero_1, zero_1, zero_1]).unwrap();
+
+        let mut tree = NaryMerkleTree::new(2, zero, 5).unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(tree.insert(*leaf), Ok(i));
+        }
+        assert_eq!(tree.root(), expected);
+        assert_eq!(tree.zeros()[1], zero_1);
+
+        let other = NaryMerkleTree::with_leaves(2, zero, 5, &leaves).unwrap();
+        assert_eq!(other.root(), expected);
+        assert_eq!(other.leaves(), leaves);
+
+        // Updating and deleting match a tree built from the resulting leaves.
+        tree.update(6, Fr::from(70)).unwrap();
+        tree.delete(0).unwrap();
+        let leaves = [9, 2, 3, 4, 5, 6, 70].map(Fr::from);
+        let other = NaryMerkleTree::with_leaves(2, zero, 5, &leaves).unwrap();
+        assert_eq!(tree.root(), other.root());
+    }
+
+    #[test]
+    fn test_nary_tree_maci() {
+        // zeros[1..=2] of MACI's MerkleQuinary0 and MerkleQuinaryMaci, the zero leaf of the
+        // latter being NOTHING_UP_MY_SLEEVE, keccak256("Maci") mod p.
+        for (zero, expected) in [
+            (
+                "0",
+                [
+                    "14655542659562014735865511769057053982292279840403315552050801315682099828156",
+                    "19261153649140605024552417994922546473530072875902678653210025980873274131905",
+                ],
+            ),
+            (
+                "8370432830353022751713833565135785980866757267633941821328460903436894336785",
+                [
+                    "12915444503621073454579416579430905206970714557680052030066757042249102605307",
+                    "15825388848727206932541662858173052318786639683743459477657913288690190505308",
+                ],
+            ),
+        ] {
+            let tree = NaryMerkleTree::new(2, dec_to_field::<Fr>(zero).unwrap(), 5).unwrap();
+            assert_eq!(field_to_dec(&tree.zeros()[1]), expected[0]);
+            assert_eq!(field_to_dec(&tree.root()), expected[1]);
+        }
+    }
+
+    #[test]
+    fn test_nary_binary() {
+        // Arity 2 is the binary tree.
+        let leaves: Vec<Fr> = (0..6).map(Fr::from).collect();
+        let tree = NaryMerkleTree::with_leaves(3, Fr::zero(), 2, &leaves).unwrap();
+        let expected = MerkleTree::with_leaves(3, Fr::zero(), &leaves).unwrap();
+        assert_eq!(tree.root(), expected.root());
+
+        let proof = tree.proof(3).unwrap();
+        let expected = expected.proof(3).unwrap();
+        assert_eq!(proof.path_indices, expected.path_indices);
+        assert_eq!(proof.siblings.concat(), expected.siblings);
+    }
+
+    #[test]
+    fn test_nary_proof() {
+        for arity in [3, 5, 16] {
+            let leaves: Vec<Fr> = (0..20).map(Fr::from).collect();
+            let tree = NaryMerkleTree::with_leaves(3, Fr::zero(), arity, &leaves).unwrap();
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(i).unwrap();
+                assert_eq!(proof.leaf, *leaf);
+                assert_eq!(proof.path_indices[0], i % arity);
+                assert!(proof.siblings.iter().all(|item| item.len() == arity - 1));
+                assert!(proof.verify());
+            }
+
+            let mut wrong = tree.proof(7).unwrap();
+            wrong.path_indices[1] = (wrong.path_indices[1] + 1) % arity;
+            assert!(!wrong.verify());
+
+            let mut wrong = tree.proof(7).unwrap();
+            wrong.path_indices[0] = arity;
+            assert_eq!(wrong.compute_root(), None);
+        }
+    }
+
+    #[test]
+    fn test_nary_errors() {
+        assert_eq!(
+            NaryMerkleTree::<Fr>::new(2, Fr::zero(), 1).unwrap_err(),
+            PoseidonError::UnsupportedArity(1)
+        );
+        assert_eq!(
+            NaryMerkleTree::<Fr>::new(2, Fr::zero(), 17).unwrap_err(),
+            PoseidonError::UnsupportedArity(17)
+        );
+        assert_eq!(
+            NaryMerkleTree::<Fr>::new(0, Fr::zero(), 5).unwrap_err(),
+            PoseidonError::InvalidDepth(0)
+        );
+        assert_eq!(
+            NaryMerkleTree::<Fr>::new(30, Fr::zero(), 16).unwrap_err(),
+            PoseidonError::InvalidDepth(30)
+        );
+
+        let mut tree = NaryMerkleTree::new(1, Fr::zero(), 3).unwrap();
+        for _ in 0..3 {
+            tree.insert(Fr::one()).unwrap();
+        }
+        assert_eq!(tree.insert(Fr::one()), Err(PoseidonError::TreeFull));
+        assert_eq!(
+            tree.proof(3).unwrap_err(),
+            PoseidonError::IndexOutOfRange { index: 3, len: 3 }
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// Derives circomlib's optimized constants from the plain mds matrix and round constants.
+// Partial round constants are moved through the inverse mds into a single scalar per round, and
+// the partial round matrices are factored into a pre-sparse matrix P and sparse matrices S
+// (Poseidon paper, appendix B).
+
+use super::*;
+use halo2::halo2curves::ff::PrimeField;
+use params::PoseidonParams;
+
+/// Computes the optimized constants from the round constants `(n_rounds_f + n_rounds_p) * t`
+/// and the t x t mds matrix, both as generated by the reference script (see `grain`).
+/// Returns None if a matrix to invert is singular.
+pub fn optimize<F: PrimeField>(
+    c: &[F],
+    m: &[Vec<F>],
+    n_rounds_f: usize,
+    n_rounds_p: usize,
+) -> Option<PoseidonParams<F>> {
+    let t = m.len();
+    let h = n_rounds_f / 2;
+    assert_eq!(
+        c.len(),
+        (n_rounds_f + n_rounds_p) * t,
+        "Invalid parameters!"
+    );
+
+    let rounds: Vec<&[F]> = c.chunks(t).collect();
+    let m_inv = invert(m)?;
+
+    // First round constants are added as they are, the next ones are moved through the mds.
+    let mut compressed = rounds[0].to_vec();
+    for round in &rounds[1..h] {
+        compressed.extend(mul_vec(&m_inv, round));
+    }
+
+    // Partial round constants are folded backward, keeping a single scalar per round.
+    let mut scalars = vec![F::ZERO; n_rounds_p];
+    let mut acc = rounds[h + n_rounds_p].to_vec();
+    for r in (h..h + n_rounds_p).rev() {
+        let u = mul_vec(&m_inv, &acc);
+        scalars[r - h] = u[0];
+        acc = rounds[r].to_vec();
+        for i in 1..t {
+            acc[i] += u[i];
+        }
+    }
+    compressed.extend(mul_vec(&m_inv, &acc));
+    compressed.extend(scalars);
+
+    for round in &rounds[h + n_rounds_p + 1..] {
+        compressed.extend(mul_vec(&m_inv, round));
+    }
+
+    // Partial round matrices are factored backward into sparse matrices and a pre-sparse one.
+    let mut sparse = vec![Vec::new(); n_rounds_p];
+    let mut x = m.to_vec();
+    for r in (0..n_rounds_p).rev() {
+        let x_hat: Vec<Vec<F>> = x[1..].iter().map(|row| row[1..].to_vec()).collect();
+        let x_hat_inv = invert(&x_hat)?;
+
+        let mut s = vec![x[0][0]];
+        s.extend((0..t - 1).map(|j| (0..t - 1).map(|i| x[0][i + 1] * x_hat_inv[i][j]).sum::<F>()));
+        s.extend(x[1..].iter().map(|row| row[0]));
+        sparse[r] = s;
+
+        let mut a = identity(t);
+        for i in 1..t {
+            a[i][1..].copy_from_slice(&x_hat[i - 1]);
+        }
+        x = mul(&a, m);
+    }
+
+    Some(PoseidonParams {
+        t,
+        n_rounds_f,
+        n_rounds_p,
+        c: compressed,
+        s: sparse.concat(),
+        m: transpose(m),
+        p: transpose(&x),
+    })
+}
+
+pub(crate) fn identity<F: PrimeField>(n: usize) -> Vec<Vec<F>> {
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| if i == j { F::ONE } else { F::ZERO })
+                .collect()
+        })
+        .collect()
+}
+
+fn transpose<F: PrimeField>(a: &[Vec<F>]) -> Vec<Vec<F>> {
+    (0..a[0].len())
+        .map(|j| a.iter().map(|row| row[j]).collect())
+        .collect()
+}
+
+pub(crate) fn mul<F: PrimeField>(a: &[Vec<F>], b: &[Vec<F>]) -> Vec<Vec<F>> {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|j| row.iter().zip(b).map(|(x, b_row)| *x * b_row[j]).sum())
+                .collect()
+        })
+        .collect()
+}
+
+pub(crate) fn mul_vec<F: PrimeField>(a: &[Vec<F>], v: &[F]) -> Vec<F> {
+    a.iter()
+        .map(|row| row.iter().zip(v).map(|(x, y)| *x * y).sum())
+        .collect()
+}
+
+/// Inverts a square matrix with Gauss-Jordan elimination, returns None if it is singular.
+fn invert<F: PrimeField>(a: &[Vec<F>]) -> Option<Vec<Vec<F>>> {
+    let n = a.len();
+    let mut a = a.to_vec();
+    let mut inv = identity(n);
+
+    for col in 0..n {
+        let pivot = (col..n).find(|&row| !bool::from(a[row][col].is_zero()))?;
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let factor = a[col][col].invert().unwrap();
+        for j in 0..n {
+            a[col][j] *= factor;
+            inv[col][j] *= factor;
+        }
+
+        for row in 0..n {
+            if row != col {
+                let factor = a[row][col];
+                for j in 0..n {
+                    let (x, y) = (a[col][j], inv[col][j]);
+                    a[row][j] -= factor * x;
+                    inv[row][j] -= factor * y;
+                }
+            }
+        }
+    }
+
+    Some(inv)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadgets::{
+        constants::{N_ROUNDS_F, N_ROUNDS_P},
+        grain::bn254_parameters,
+        utils::{poseidon_c, poseidon_m, poseidon_p, poseidon_s},
+    };
+    use halo2::halo2curves::bn256::Fr;
+
+    #[test]
+    fn test_optimize_shipped_constants() {
+        for t in 2..=17 {
+            let n_rounds_p = N_ROUNDS_P[t - 2];
+            let (c, m) = bn254_parameters(t);
+            let params = optimize(c, m, N_ROUNDS_F, n_rounds_p).unwrap();
+
+            assert_eq!(params.c, poseidon_c(t));
+            assert_eq!(params.s, poseidon_s(t));
+            assert_eq!(params.m, poseidon_m(t));
+            assert_eq!(params.p, poseidon_p(t));
+        }
+    }
+
+    #[test]
+    fn test_invert() {
+        let a: Vec<Vec<Fr>> = vec![
+            vec![Fr::from(0), Fr::from(2), Fr::from(3)],
+            vec![Fr::from(4), Fr::from(5), Fr::from(6)],
+            vec![Fr::from(7), Fr::from(8), Fr::from(10)],
+        ];
+
+        let a_inv = invert(&a).unwrap();
+
+        assert_eq!(mul(&a, &a_inv), identity(3));
+        assert_eq!(mul(&a_inv, &a), identity(3));
+
+        let singular = vec![
+            vec![Fr::from(1), Fr::from(2)],
+            vec![Fr::from(2), Fr::from(4)],
+        ];
+        assert_eq!(invert(&singular), None);
+    }
+}
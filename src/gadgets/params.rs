@@ -0,0 +1,282 @@
+use std::sync::OnceLock;
+
+use super::*;
+use constants::{N_ROUNDS_F, N_ROUNDS_P};
+use error::PoseidonError;
+use grain::generate_parameters;
+use halo2::halo2curves::{bn256, ff::PrimeField, pasta};
+use optimize::optimize;
+use utils::{poseidon_c, poseidon_m, poseidon_p, poseidon_s};
+
+/// Parameters of the permutation for a width t, laid out as in circomlib.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoseidonParams<F> {
+    pub(crate) t: usize,
+    pub(crate) n_rounds_f: usize,
+    pub(crate) n_rounds_p: usize,
+    pub(crate) c: Vec<F>,
+    pub(crate) s: Vec<F>,
+    pub(crate) m: Vec<Vec<F>>,
+    pub(crate) p: Vec<Vec<F>>,
+}
+
+impl<F: PrimeField> PoseidonParams<F> {
+    /// Generates the parameters with the reference script for x^5 and optimizes them.
+    pub fn generate(t: usize, n_rounds_f: usize, n_rounds_p: usize) -> Result<Self, PoseidonError> {
+        if t < 2 {
+            return Err(PoseidonError::UnsupportedArity(t.saturating_sub(1)));
+        }
+
+        let (c, m) = generate_parameters::<F>(0, t, n_rounds_f, n_rounds_p);
+        Self::from_raw(&c, &m, n_rounds_f, n_rounds_p)
+    }
+
+    /// Optimizes the round constants `(n_rounds_f + n_rounds_p) * t` and the t x t mds matrix,
+    /// both laid out as generated by the reference script.
+    /// The full rounds are split in two halves of at least two rounds each.
+    pub fn from_raw(
+        c: &[F],
+        m: &[Vec<F>],
+        n_rounds_f: usize,
+        n_rounds_p: usize,
+    ) -> Result<Self, PoseidonError> {
+        let t = m.len();
+        let valid = t >= 2
+            && m.iter().all(|row| row.len() == t)
+            && n_rounds_f >= 4
+            && n_rounds_f & 1 == 0
+            && n_rounds_p >= 1
+            && c.len() == (n_rounds_f + n_rounds_p) * t;
+
+        if !valid {
+            return Err(PoseidonError::InvalidParameters);
+        }
+
+        optimize(c, m, n_rounds_f, n_rounds_p).ok_or(PoseidonError::InvalidParameters)
+    }
+
+    /// Width of the permutation.
+    pub fn t(&self) -> usize {
+        self.t
+    }
+
+    /// Number of full rounds.
+    pub fn n_rounds_f(&self) -> usize {
+        self.n_rounds_f
+    }
+
+    /// Number of partial rounds.
+    pub fn n_rounds_p(&self) -> usize {
+        self.n_rounds_p
+    }
+
+    /// Compressed round constants, `n_rounds_f * t + n_rounds_p` entries.
+    pub fn c(&self) -> &[F] {
+        &self.c
+    }
+
+    /// Sparse partial round matrices, `2 * t - 1` entries per partial round.
+    pub fn s(&self) -> &[F] {
+        &self.s
+    }
+
+    /// Mds matrix, transposed.
+    pub fn m(&self) -> &[Vec<F>] {
+        &self.m
+    }
+
+    /// Pre-sparse matrix applied before the partial rounds, transposed.
+    pub fn p(&self) -> &[Vec<F>] {
+        &self.p
+    }
+}
+
+impl PoseidonParams<bn256::Fr> {
+    /// Returns circomlib's parameters for BN254.
+    pub fn circomlib(t: usize) -> Result<Self, PoseidonError> {
+        if !(2..=17).contains(&t) {
+            return Err(PoseidonError::UnsupportedArity(t.saturating_sub(1)));
+        }
+
+        Ok(Self {
+            t,
+            n_rounds_f: N_ROUNDS_F,
+            n_rounds_p: N_ROUNDS_P[t - 2],
+            c: poseidon_c(t),
+            s: poseidon_s(t),
+            m: poseidon_m(t),
+            p: poseidon_p(t),
+        })
+    }
+}
+
+/// Fields with a parameter set for every width t = 2..17, whose elements fit in 32 bytes.
+pub trait PoseidonField: PrimeField {
+    /// Returns the parameters for the width t, computed once and shared by every caller.
+    fn params(t: usize) -> Result<&'static PoseidonParams<Self>, PoseidonError>;
+}
+
+// Parameters are cached per width on first use.
+macro_rules! impl_poseidon_field {
+    ($field:ty, $params:expr) => {
+        impl PoseidonField for $field {
+            fn params(t: usize) -> Result<&'static PoseidonParams<Self>, PoseidonError> {
+                static PARAMS: [OnceLock<PoseidonParams<$field>>; 16] =
+                    [const { OnceLock::new() }; 16];
+
+                if !(2..=17).contains(&t) {
+                    return Err(PoseidonError::UnsupportedArity(t.saturating_sub(1)));
+                }
+
+                let params: fn(usize) -> Result<PoseidonParams<$field>, PoseidonError> = $params;
+                Ok(PARAMS[t - 2].get_or_init(|| params(t).expect("Invalid parameters!")))
+            }
+        }
+    };
+}
+
+impl_poseidon_field!(bn256::Fr, PoseidonParams::circomlib);
+
+// Other ~255-bit fields use the same round numbers, generated with the reference script.
+fn generate<F: PrimeField>(t: usize) -> Result<PoseidonParams<F>, PoseidonError> {
+    PoseidonParams::generate(t, N_ROUNDS_F, N_ROUNDS_P[t - 2])
+}
+
+impl_poseidon_field!(pasta::Fp, generate);
+impl_poseidon_field!(pasta::Fq, generate);
+#[cfg(feature = "bls12_381")]
+impl_poseidon_field!(bls12_381::Scalar, generate);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadgets::{
+        templates::{permute_with_params, Poseidon},
+        utils::hex_to_field,
+    };
+
+    #[test]
+    fn test_params_circomlib() {
+        for t in 2..=17 {
+            let params = bn256::Fr::params(t).unwrap();
+            assert_eq!(params.c.len(), N_ROUNDS_F * t + params.n_rounds_p);
+            assert_eq!(params.s.len(), (t * 2 - 1) * params.n_rounds_p);
+            assert_eq!(params.m.len(), t);
+            assert_eq!(params.p.len(), t);
+        }
+    }
+
+    fn run<F: PoseidonField>() {
+        // Different inputs give different outputs.
+        let inputs = vec![F::ONE, F::from(2)];
+        let output = Poseidon::new(inputs.clone()).poseidon_ex(F::ZERO, 1);
+        let other = Poseidon::new(vec![F::ONE, F::from(3)]).poseidon_ex(F::ZERO, 1);
+        assert_ne!(output.unwrap(), other.unwrap());
+
+        // The optimized permutation agrees with the reference one.
+        let (c, m) = generate_parameters::<F>(0, 3, N_ROUNDS_F, N_ROUNDS_P[1]);
+        let state = [F::ZERO, F::ONE, F::from(2)];
+        let expected = reference::permute(&state, &c, &m, N_ROUNDS_F, N_ROUNDS_P[1]);
+        assert_eq!(
+            Poseidon::new(inputs).poseidon_ex_multi(F::ZERO, 3),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn test_params_fields() {
+        run::<bn256::Fr>();
+        run::<pasta::Fp>();
+        run::<pasta::Fq>();
+        #[cfg(feature = "bls12_381")]
+        run::<bls12_381::Scalar>();
+    }
+
+    /// Permutes [0, 1, .., t - 1] with the parameters.
+    fn permute_range<F: PrimeField, const T: usize>(params: &PoseidonParams<F>) -> Vec<F> {
+        let mut state: [F; T] = std::array::from_fn(|i| F::from(i as u64));
+        permute_with_params(params, &mut state).unwrap();
+        state.to_vec()
+    }
+
+    fn from_hex<F: PrimeField>(items: &[&str]) -> Vec<F> {
+        items
+            .iter()
+            .map(|item| hex_to_field(item).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_params_pasta_vectors() {
+        // halo2_gadgets' P128Pow5T3 test vectors, from the reference script run by
+        // pasta-hadeshash: `generate_parameters_grain.sage 1 0 255 3 8 56 p`.
+        let params = PoseidonParams::<pasta::Fp>::generate(3, 8, 56).unwrap();
+        let expected = from_hex(&[
+            "0x2a526acd0b64b45394efb364f966240ff7e69a71d0b642a0aeb1bc024aeca456",
+            "0x13c5d1568b4aa43076ff7dae343d5512dcd42e7fbed9dafe012a3e9628e5b82a",
+            "0x0a49c868c6976544256fcd597984561af7cfdfe1bda42c7b359029a1d34e9ddd",
+        ]);
+        assert_eq!(permute_range::<_, 3>(&params), expected);
+
+        let params = PoseidonParams::<pasta::Fq>::generate(3, 8, 56).unwrap();
+        let expected = from_hex(&[
+            "0x315a1f4cdb942f7ceddd74f22f8f2ff74d43d1973dd336c60eb08ea813bebe59",
+            "0x3be475f2d7642bde642adee0dd13aa48413ee0eb7bbd2198f9f126e61ea165f1",
+            "0x25ab8aece9537168117fdb2420d8ea605019bfd4e0423fa014d542372a7ba0d9",
+        ]);
+        assert_eq!(permute_range::<_, 3>(&params), expected);
+    }
+
+    #[cfg(feature = "bls12_381")]
+    #[test]
+    fn test_params_bls12_381_vectors() {
+        // test_vectors.txt of the reference implementation, poseidonperm_x5_255_3 and
+        // poseidonperm_x5_255_5, whose round numbers are the defaults for t = 3 and t = 5.
+        let expected = from_hex(&[
+            "0x28ce19420fc246a05553ad1e8c98f5c9d67166be2c18e9e4cb4b4e317dd2a78a",
+            "0x51f3e312c95343a896cfd8945ea82ba956c1118ce9b9859b6ea56637b4b1ddc4",
+            "0x3b2b69139b235626a0bfb56c9527ae66a7bf486ad8c11c14d1da0c69bbe0f79a",
+        ]);
+        assert_eq!(
+            permute_range::<_, 3>(bls12_381::Scalar::params(3).unwrap()),
+            expected
+        );
+
+        let expected = from_hex(&[
+            "0x2a918b9c9f9bd7bb509331c81e297b5707f6fc7393dcee1b13901a0b22202e18",
+            "0x65ebf8671739eeb11fb217f2d5c5bf4a0c3f210e3f3cd3b08b5db75675d797f7",
+            "0x2cc176fc26bc70737a696a9dfd1b636ce360ee76926d182390cdb7459cf585ce",
+            "0x4dc4e29d283afd2a491fe6aef122b9a968e74eff05341f3cc23fda1781dcb566",
+            "0x03ff622da276830b9451b88b85e6184fd6ae15c8ab3ee25a5667be8592cce3b1",
+        ]);
+        assert_eq!(
+            permute_range::<_, 5>(bls12_381::Scalar::params(5).unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_params_errors() {
+        for t in [0usize, 1, 18] {
+            let expected = PoseidonError::UnsupportedArity(t.saturating_sub(1));
+            assert_eq!(bn256::Fr::params(t).unwrap_err(), expected);
+            assert_eq!(pasta::Fp::params(t).unwrap_err(), expected);
+        }
+
+        let (c, m) = grain::bn254_parameters(3);
+        let invalid = Err(PoseidonError::InvalidParameters);
+        assert_eq!(PoseidonParams::from_raw(c, m, N_ROUNDS_F, 56), invalid);
+        assert_eq!(PoseidonParams::from_raw(c, m, 2, 63), invalid);
+        assert_eq!(
+            PoseidonParams::from_raw(c, &m[1..], N_ROUNDS_F, 57),
+            invalid
+        );
+
+        // A singular mds matrix cannot be optimized.
+        let singular = vec![vec![bn256::Fr::one(); 3]; 3];
+        assert_eq!(
+            PoseidonParams::from_raw(c, &singular, N_ROUNDS_F, 57),
+            invalid
+        );
+    }
+}
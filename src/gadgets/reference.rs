@@ -0,0 +1,95 @@
+// Textbook Poseidon permutation over the unoptimized round constants and mds matrix, used to
+// check circomlib's optimized form in `templates`.
+
+use halo2::halo2curves::ff::PrimeField;
+
+/// Applies the permutation to the state: every round adds its round constants, applies the
+/// S-box x^5 to the whole state in full rounds or to the first element in partial rounds, and
+/// multiplies by the mds matrix. Constants and matrix are taken as generated by the reference
+/// script (see `grain`).
+pub fn permute<F: PrimeField>(
+    state: &[F],
+    c: &[F],
+    m: &[Vec<F>],
+    n_rounds_f: usize,
+    n_rounds_p: usize,
+) -> Vec<F> {
+    let t = state.len();
+    assert_eq!(m.len(), t, "Invalid parameters!");
+    assert_eq!(
+        c.len(),
+        (n_rounds_f + n_rounds_p) * t,
+        "Invalid parameters!"
+    );
+
+    let mut state = state.to_vec();
+
+    for (r, round) in c.chunks(t).enumerate() {
+        for (item, c) in state.iter_mut().zip(round) {
+            *item += c;
+        }
+
+        if r < n_rounds_f / 2 || r >= n_rounds_f / 2 + n_rounds_p {
+            state.iter_mut().for_each(|item| *item = sigma(*item));
+        } else {
+            state[0] = sigma(state[0]);
+        }
+
+        state = m
+            .iter()
+            .map(|row| row.iter().zip(&state).map(|(x, y)| *x * y).sum())
+            .collect();
+    }
+
+    state
+}
+
+/// Exp of S-box.
+fn sigma<F: PrimeField>(item: F) -> F {
+    let item_2 = item.square();
+    item * item_2.square()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gadgets::{
+        constants::{N_ROUNDS_F, N_ROUNDS_P},
+        grain::bn254_parameters,
+        templates::Poseidon,
+    };
+    use halo2::halo2curves::{bn256::Fr, ff::Field};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_reference_poseidon() {
+        // poseidon([1, 2]) from circomlibjs.
+        let (c, m) = bn254_parameters(3);
+        let state = [Fr::zero(), Fr::from(1), Fr::from(2)];
+        let output = permute(&state, c, m, N_ROUNDS_F, N_ROUNDS_P[1]);
+        let expected = Fr::from_str_vartime(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+
+        assert_eq!(output[0], expected);
+    }
+
+    #[test]
+    fn test_reference_differential() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for t in 2..=17 {
+            let n_rounds_p = N_ROUNDS_P[t - 2];
+            let (c, m) = bn254_parameters(t);
+
+            for _ in 0..4 {
+                let state: Vec<Fr> = (0..t).map(|_| Fr::random(&mut rng)).collect();
+                let expected = permute(&state, c, m, N_ROUNDS_F, n_rounds_p);
+
+                let poseidon = Poseidon::new(state[1..].to_vec());
+                assert_eq!(poseidon.poseidon_ex_multi(state[0], t).unwrap(), expected);
+            }
+        }
+    }
+}
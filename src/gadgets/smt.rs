@@ -0,0 +1,669 @@
+use std::mem;
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use merkle::hash_pair;
+use params::PoseidonField;
+use templates::permute;
+use utils::field_to_bytes_le;
+
+/// Returns Poseidon(key, value, 1), the leaf hash of circomlib's smt.
+fn hash_leaf<F: PoseidonField>(key: F, value: F) -> F {
+    let mut state = [F::ZERO, key, value, F::ONE];
+    permute(&mut state).expect("Invalid parameters!");
+    state[0]
+}
+
+/// Returns the bit of the key choosing the child at the given level, least significant first.
+fn bit(key: &[u8; 32], level: usize) -> bool {
+    (key[level / 8] >> (level % 8)) & 1 == 1
+}
+
+/// Returns the number of levels of a path, trailing zero siblings being padding.
+fn n_levels<F: PoseidonField>(siblings: &[F]) -> usize {
+    siblings
+        .iter()
+        .rposition(|item| *item != F::ZERO)
+        .map_or(0, |i| i + 1)
+}
+
+/// Checks that a path is not longer than the bits of the keys.
+fn valid_levels<F: PoseidonField>(siblings: &[F]) -> bool {
+    siblings.len() <= F::NUM_BITS as usize
+}
+
+/// Hashes a node up to the root with the siblings of its path, ignoring trailing zero siblings
+/// as circomlib's smt templates.
+fn root_from<F: PoseidonField>(siblings: &[F], key: &[u8; 32], node: F) -> F {
+    let mut node = node;
+    for level in (0..n_levels(siblings)).rev() {
+        node = if bit(key, level) {
+            hash_pair(siblings[level], node)
+        } else {
+            hash_pair(node, siblings[level])
+        };
+    }
+    node
+}
+
+/// Returns the subtree at the given level holding two leaves that share the bits above it, or
+/// `None` if their keys do not differ below it.
+fn split_hash<F: PoseidonField>(a: (F, F), b: (F, F), level: usize) -> Option<F> {
+    let key_a = field_to_bytes_le(&a.0);
+    let key_b = field_to_bytes_le(&b.0);
+    let split =
+        (level..F::NUM_BITS as usize).find(|level| bit(&key_a, *level) != bit(&key_b, *level))?;
+
+    let (leaf_a, leaf_b) = (hash_leaf(a.0, a.1), hash_leaf(b.0, b.1));
+    let mut node = if bit(&key_a, split) {
+        hash_pair(leaf_b, leaf_a)
+    } else {
+        hash_pair(leaf_a, leaf_b)
+    };
+    for level in (level..split).rev() {
+        node = if bit(&key_a, level) {
+            hash_pair(F::ZERO, node)
+        } else {
+            hash_pair(node, F::ZERO)
+        };
+    }
+    Some(node)
+}
+
+/// Pads the siblings with zeros to the number of levels of a circuit.
+fn pad<F: PoseidonField>(siblings: &[F], n_levels: usize) -> Result<Vec<F>, PoseidonError> {
+    if siblings.len() > n_levels {
+        return Err(PoseidonError::InvalidDepth(n_levels));
+    }
+
+    let mut padded = siblings.to_vec();
+    padded.resize(n_levels, F::ZERO);
+    Ok(padded)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node<F> {
+    Empty,
+    Leaf {
+        key: F,
+        value: F,
+        hash: F,
+    },
+    Middle {
+        left: Box<Node<F>>,
+        right: Box<Node<F>>,
+        hash: F,
+    },
+}
+
+impl<F: PoseidonField> Node<F> {
+    fn leaf(key: F, value: F) -> Self {
+        let hash = hash_leaf(key, value);
+        Node::Leaf { key, value, hash }
+    }
+
+    fn middle(left: Self, right: Self) -> Self {
+        let hash = hash_pair(left.hash(), right.hash());
+        Node::Middle {
+            left: Box::new(left),
+            right: Box::new(right),
+            hash,
+        }
+    }
+
+    fn hash(&self) -> F {
+        match self {
+            Node::Empty => F::ZERO,
+            Node::Leaf { hash, .. } | Node::Middle { hash, .. } => *hash,
+        }
+    }
+
+    fn key(&self) -> [u8; 32] {
+        match self {
+            Node::Leaf { key, .. } => field_to_bytes_le(key),
+            _ => panic!("Invalid parameters!"),
+        }
+    }
+
+    /// Inserts a leaf whose key is not in the subtree, pushing down the leaf it meets until
+    /// their keys differ.
+    fn insert(self, leaf: Self, key: &[u8; 32], level: usize) -> Self {
+        match self {
+            Node::Empty => leaf,
+            Node::Leaf { .. } => Self::split(self, leaf, level),
+            Node::Middle { left, right, .. } => {
+                if bit(key, level) {
+                    Self::middle(*left, right.insert(leaf, key, level + 1))
+                } else {
+                    Self::middle(left.insert(leaf, key, level + 1), *right)
+                }
+            }
+        }
+    }
+
+    fn split(a: Self, b: Self, level: usize) -> Self {
+        let (bit_a, bit_b) = (bit(&a.key(), level), bit(&b.key(), level));
+        match (bit_a, bit_b) {
+            (false, true) => Self::middle(a, b),
+            (true, false) => Self::middle(b, a),
+            (false, false) => Self::middle(Self::split(a, b, level + 1), Node::Empty),
+            (true, true) => Self::middle(Node::Empty, Self::split(a, b, level + 1)),
+        }
+    }
+
+    /// Replaces the value of the leaf with the key, which is in the subtree.
+    fn update(self, value: F, key: &[u8; 32], level: usize) -> Self {
+        match self {
+            Node::Leaf { key, .. } => Self::leaf(key, value),
+            Node::Middle { left, right, .. } => {
+                if bit(key, level) {
+                    Self::middle(*left, right.update(value, key, level + 1))
+                } else {
+                    Self::middle(left.update(value, key, level + 1), *right)
+                }
+            }
+            Node::Empty => panic!("Invalid parameters!"),
+        }
+    }
+
+    /// Removes the leaf with the key, which is in the subtree, moving up a leaf left alone.
+    fn remove(self, key: &[u8; 32], level: usize) -> Self {
+        match self {
+            Node::Leaf { .. } => Node::Empty,
+            Node::Middle { left, right, .. } => {
+                let (left, right) = if bit(key, level) {
+                    (*left, right.remove(key, level + 1))
+                } else {
+                    (left.remove(key, level + 1), *right)
+                };
+
+                match (&left, &right) {
+                    (Node::Empty, Node::Empty) => Node::Empty,
+                    (Node::Empty, Node::Leaf { .. }) => right,
+                    (Node::Leaf { .. }, Node::Empty) => left,
+                    _ => Self::middle(left, right),
+                }
+            }
+            Node::Empty => panic!("Invalid parameters!"),
+        }
+    }
+}
+
+/// Sparse Merkle tree compatible with circomlib's smt and iden3's merkletree: leaves hash
+/// to Poseidon(key, value, 1), middle nodes to Poseidon(left, right) and empty nodes to zero.
+/// A leaf sits at the first level where its key bits, least significant first, no longer
+/// match another leaf, so the root only depends on the stored key-value pairs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseMerkleTree<F = Fr> {
+    root: Node<F>,
+}
+
+/// Inclusion or non-inclusion proof of a key, with the inputs of circomlib's SMTVerifier
+/// (`fnc` being 0 for inclusion and 1 for non-inclusion).
+///
+/// For non-inclusion, `old_key` and `old_value` are the leaf found on the path of the key, or
+/// zero with `is_old0` set when the path ends in an empty node. They are zero for inclusion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmtProof<F = Fr> {
+    pub found: bool,
+    pub root: F,
+    pub siblings: Vec<F>,
+    pub key: F,
+    pub value: F,
+    pub old_key: F,
+    pub old_value: F,
+    pub is_old0: bool,
+}
+
+/// Operation of circomlib's SMTProcessor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtFunction {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Transition between two roots with the inputs of circomlib's SMTProcessor, as returned by
+/// circomlibjs `insert`, `update` and `delete`.
+///
+/// For deletion, `new_key` and `new_value` are the removed leaf (`delKey` and `delValue`) and
+/// the other fields describe the path of the key in the new tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmtUpdate<F = Fr> {
+    pub function: SmtFunction,
+    pub old_root: F,
+    pub new_root: F,
+    pub siblings: Vec<F>,
+    pub old_key: F,
+    pub old_value: F,
+    pub is_old0: bool,
+    pub new_key: F,
+    pub new_value: F,
+}
+
+impl<F: PoseidonField> SparseMerkleTree<F> {
+    /// Creates an empty tree, whose root is zero.
+    pub fn new() -> Self {
+        Self { root: Node::Empty }
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.root.hash()
+    }
+
+    /// Returns the value stored under the key.
+    pub fn get(&self, key: F) -> Option<F> {
+        let proof = self.proof(key);
+        proof.found.then_some(proof.value)
+    }
+
+    /// Returns the inclusion or non-inclusion proof of the key, as circomlibjs `find`.
+    pub fn proof(&self, key: F) -> SmtProof<F> {
+        let bits = field_to_bytes_le(&key);
+        let mut siblings = Vec::new();
+        let mut node = &self.root;
+
+        while let Node::Middle { left, right, .. } = node {
+            if bit(&bits, siblings.len()) {
+                siblings.push(left.hash());
+                node = right;
+            } else {
+                siblings.push(right.hash());
+                node = left;
+            }
+        }
+
+        let mut proof = SmtProof {
+            found: false,
+            root: self.root(),
+            siblings,
+            key,
+            value: F::ZERO,
+            old_key: F::ZERO,
+            old_value: F::ZERO,
+            is_old0: false,
+        };
+        match node {
+            Node::Leaf {
+                key: old_key,
+                value,
+                ..
+            } if *old_key == key => {
+                proof.found = true;
+                proof.value = *value;
+            }
+            Node::Leaf { key, value, .. } => {
+                proof.old_key = *key;
+                proof.old_value = *value;
+            }
+            _ => proof.is_old0 = true,
+        }
+        proof
+    }
+
+    /// Inserts a new key.
+    pub fn insert(&mut self, key: F, value: F) -> Result<SmtUpdate<F>, PoseidonError> {
+        let proof = self.proof(key);
+        if proof.found {
+            return Err(PoseidonError::KeyExists);
+        }
+
+        let bits = field_to_bytes_le(&key);
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = root.insert(Node::leaf(key, value), &bits, 0);
+
+        Ok(SmtUpdate::new(
+            SmtFunction::Insert,
+            proof,
+            self.root(),
+            value,
+        ))
+    }
+
+    /// Replaces the value of a key in the tree.
+    pub fn update(&mut self, key: F, value: F) -> Result<SmtUpdate<F>, PoseidonError> {
+        let proof = self.proof(key);
+        if !proof.found {
+            return Err(PoseidonError::KeyNotFound);
+        }
+
+        let bits = field_to_bytes_le(&key);
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = root.update(value, &bits, 0);
+
+        Ok(SmtUpdate::new(
+            SmtFunction::Update,
+            proof,
+            self.root(),
+            value,
+        ))
+    }
+
+    /// Removes a key from the tree.
+    pub fn delete(&mut self, key: F) -> Result<SmtUpdate<F>, PoseidonError> {
+        let old = self.proof(key);
+        if !old.found {
+            return Err(PoseidonError::KeyNotFound);
+        }
+
+        let bits = field_to_bytes_le(&key);
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = root.remove(&bits, 0);
+
+        let mut update = SmtUpdate::new(SmtFunction::Delete, self.proof(key), old.root, old.value);
+        mem::swap(&mut update.old_root, &mut update.new_root);
+        Ok(update)
+    }
+}
+
+impl<F: PoseidonField> Default for SparseMerkleTree<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: PoseidonField> SmtProof<F> {
+    /// Returns the siblings padded with zeros to the number of levels of a circuit.
+    pub fn padded_siblings(&self, n_levels: usize) -> Result<Vec<F>, PoseidonError> {
+        pad(&self.siblings, n_levels)
+    }
+
+    /// Checks the proof against its root, as SMTVerifier.
+    pub fn verify(&self) -> bool {
+        if !valid_levels(&self.siblings) {
+            return false;
+        }
+
+        let node = if self.found {
+            hash_leaf(self.key, self.value)
+        } else if self.is_old0 {
+            F::ZERO
+        } else if self.old_key != self.key {
+            hash_leaf(self.old_key, self.old_value)
+        } else {
+            return false;
+        };
+
+        root_from(&self.siblings, &field_to_bytes_le(&self.key), node) == self.root
+    }
+}
+
+impl SmtFunction {
+    /// Returns the `fnc` input of SMTProcessor.
+    pub fn fnc(self) -> [bool; 2] {
+        match self {
+            SmtFunction::Insert => [true, false],
+            SmtFunction::Update => [false, true],
+            SmtFunction::Delete => [true, true],
+        }
+    }
+}
+
+impl<F: PoseidonField> SmtUpdate<F> {
+    /// Builds the transition from the proof of the key before the insertion or update, or
+    /// after the deletion.
+    fn new(function: SmtFunction, proof: SmtProof<F>, new_root: F, new_value: F) -> Self {
+        let (old_key, old_value) = match (function, proof.found, proof.is_old0) {
+            (SmtFunction::Update, ..) => (proof.key, proof.value),
+            (_, _, true) => (proof.key, F::ZERO),
+            _ => (proof.old_key, proof.old_value),
+        };
+
+        Self {
+            function,
+            old_root: proof.root,
+            new_root,
+            siblings: proof.siblings,
+            old_key,
+            old_value,
+            is_old0: proof.is_old0,
+            new_key: proof.key,
+            new_value,
+        }
+    }
+
+    /// Returns the siblings padded with zeros to the number of levels of a circuit.
+    pub fn padded_siblings(&self, n_levels: usize) -> Result<Vec<F>, PoseidonError> {
+        pad(&self.siblings, n_levels)
+    }
+
+    /// Checks that the transition leads from the old root to the new one, as SMTProcessor.
+    pub fn verify(&self) -> bool {
+        if !valid_levels(&self.siblings) {
+            return false;
+        }
+        let key = field_to_bytes_le(&self.new_key);
+
+        if self.function == SmtFunction::Update {
+            return self.old_key == self.new_key
+                && root_from(
+                    &self.siblings,
+                    &key,
+                    hash_leaf(self.old_key, self.old_value),
+                ) == self.old_root
+                && root_from(
+                    &self.siblings,
+                    &key,
+                    hash_leaf(self.new_key, self.new_value),
+                ) == self.new_root;
+        }
+
+        // A deletion is an insertion from the new root to the old one.
+        let (without, with) = match self.function {
+            SmtFunction::Delete => (self.new_root, self.old_root),
+            _ => (self.old_root, self.new_root),
+        };
+
+        let new = (self.new_key, self.new_value);
+        let (old_node, new_node) = if self.is_old0 {
+            (F::ZERO, hash_leaf(new.0, new.1))
+        } else if self.old_key != self.new_key {
+            let old = (self.old_key, self.old_value);
+            // The old leaf sits below the last non-zero sibling, whatever the padding.
+            match split_hash(old, new, n_levels(&self.siblings)) {
+                Some(node) => (hash_leaf(old.0, old.1), node),
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+
+        root_from(&self.siblings, &key, old_node) == without
+            && root_from(&self.siblings, &key, new_node) == with
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use utils::field_to_dec;
+
+    #[test]
+    fn test_smt_root() {
+        let leaf = |key: u64| hash_leaf(Fr::from(key), Fr::from(key * 10));
+
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), Fr::zero());
+
+        // A single leaf is the root.
+        tree.insert(Fr::from(1), Fr::from(10)).unwrap();
+        assert_eq!(tree.root(), leaf(1));
+
+        // 1 = 0b01 and 2 = 0b10 split at the first bit.
+        tree.insert(Fr::from(2), Fr::from(20)).unwrap();
+        assert_eq!(tree.root(), hash_pair(leaf(2), leaf(1)));
+
+        // 1 = 0b01 and 3 = 0b11 split at the second bit.
+        tree.delete(Fr::from(2)).unwrap();
+        tree.insert(Fr::from(3), Fr::from(30)).unwrap();
+        let expected = hash_pair(Fr::zero(), hash_pair(leaf(1), leaf(3)));
+        assert_eq!(tree.root(), expected);
+
+        assert_eq!(tree.get(Fr::from(3)), Some(Fr::from(30)));
+        assert_eq!(tree.get(Fr::from(2)), None);
+    }
+
+    #[test]
+    fn test_smt_vectors() {
+        // Roots of circomlibjs newMemEmptyTrie, as in go-merkletree's TestNewTree.
+        let mut tree = SparseMerkleTree::new();
+        for (key, value, root) in [
+            (
+                1,
+                2,
+                "13578938674299138072471463694055224830892726234048532520316387704878000008795",
+            ),
+            (
+                33,
+                44,
+                "5412393676474193513566895793055462193090331607895808993925969873307089394741",
+            ),
+            (
+                1234,
+                9876,
+                "14204494359367183802864593755198662203838502594566452929175967972147978322084",
+            ),
+        ] {
+            tree.insert(Fr::from(key), Fr::from(value)).unwrap();
+            assert_eq!(field_to_dec(&tree.root()), root);
+        }
+    }
+
+    #[test]
+    fn test_smt_canonical() {
+        let keys: Vec<Fr> = [111, 222, 333, 7, 15, 31, 64, 1 << 20]
+            .map(Fr::from)
+            .to_vec();
+
+        let mut tree = SparseMerkleTree::new();
+        let mut roots = vec![tree.root()];
+        for key in keys.iter() {
+            tree.insert(*key, *key + Fr::one()).unwrap();
+            roots.push(tree.root());
+        }
+
+        // The root does not depend on the insertion order.
+        let mut other = SparseMerkleTree::new();
+        for key in keys.iter().rev() {
+            other.insert(*key, *key + Fr::one()).unwrap();
+        }
+        assert_eq!(tree, other);
+
+        // Deleting in reverse order goes back through the same roots.
+        for key in keys.iter().rev() {
+            roots.pop();
+            tree.delete(*key).unwrap();
+            assert_eq!(tree.root(), *roots.last().unwrap());
+        }
+        assert_eq!(tree, SparseMerkleTree::new());
+    }
+
+    #[test]
+    fn test_smt_proof() {
+        let mut tree = SparseMerkleTree::new();
+        for key in [1, 3, 4, 8] {
+            tree.insert(Fr::from(key), Fr::from(key * 10)).unwrap();
+        }
+
+        let proof = tree.proof(Fr::from(3));
+        assert!(proof.found && proof.verify());
+        assert_eq!(proof.value, Fr::from(30));
+        assert_eq!(proof.siblings.len(), 2);
+
+        // 5 = 0b101 ends on the leaf 1 = 0b001.
+        let proof = tree.proof(Fr::from(5));
+        assert!(!proof.found && !proof.is_old0 && proof.verify());
+        assert_eq!(
+            (proof.old_key, proof.old_value),
+            (Fr::from(1), Fr::from(10))
+        );
+
+        // 2 = 0b010 ends on an empty node.
+        let proof = tree.proof(Fr::from(2));
+        assert!(!proof.found && proof.is_old0 && proof.verify());
+
+        // Padded siblings verify the same.
+        let mut padded = proof.clone();
+        padded.siblings = proof.padded_siblings(10).unwrap();
+        assert!(padded.verify());
+
+        // Paths longer than the keys are rejected, however they are padded.
+        padded.siblings = proof.padded_siblings(300).unwrap();
+        assert!(!padded.verify());
+        assert_eq!(
+            proof.padded_siblings(1),
+            Err(PoseidonError::InvalidDepth(1))
+        );
+
+        let mut wrong = tree.proof(Fr::from(3));
+        wrong.value = Fr::from(31);
+        assert!(!wrong.verify());
+
+        let mut wrong = tree.proof(Fr::from(5));
+        wrong.old_key = Fr::from(5);
+        assert!(!wrong.verify());
+    }
+
+    #[test]
+    fn test_smt_update() {
+        let mut tree = SparseMerkleTree::new();
+        let mut updates = Vec::new();
+        for key in [1, 3, 4, 8, 5, 2] {
+            updates.push(tree.insert(Fr::from(key), Fr::from(key * 10)).unwrap());
+        }
+        updates.push(tree.update(Fr::from(3), Fr::from(33)).unwrap());
+        for key in [3, 1, 8, 2, 4, 5] {
+            updates.push(tree.delete(Fr::from(key)).unwrap());
+        }
+
+        for update in updates.iter() {
+            assert!(update.verify());
+        }
+        for pair in updates.windows(2) {
+            assert_eq!(pair[0].new_root, pair[1].old_root);
+        }
+        assert_eq!(updates.last().unwrap().new_root, Fr::zero());
+
+        // Inserting next to a leaf and into an empty node.
+        assert!(!updates[1].is_old0 && updates[2].is_old0);
+        assert_eq!(updates[1].old_key, Fr::from(1));
+        assert_eq!(updates[2].old_key, Fr::from(4));
+
+        // Padding does not move the leaf the new one splits from.
+        let mut padded = updates[1].clone();
+        padded.siblings = updates[1].padded_siblings(10).unwrap();
+        assert!(padded.verify());
+
+        // Keys that share every bit below the path are rejected instead of split.
+        let mut wrong = updates[1].clone();
+        wrong.siblings = vec![Fr::one(); 253];
+        assert!(!wrong.verify());
+
+        let mut wrong = updates[6].clone();
+        wrong.new_value = Fr::from(34);
+        assert!(!wrong.verify());
+
+        let mut wrong = updates[7].clone();
+        wrong.function = SmtFunction::Insert;
+        assert!(!wrong.verify());
+        assert_eq!(SmtFunction::Delete.fnc(), [true, true]);
+    }
+
+    #[test]
+    fn test_smt_errors() {
+        let mut tree = SparseMerkleTree::new();
+        tree.insert(Fr::from(1), Fr::from(10)).unwrap();
+
+        assert_eq!(
+            tree.insert(Fr::from(1), Fr::from(11)),
+            Err(PoseidonError::KeyExists)
+        );
+        assert_eq!(
+            tree.update(Fr::from(2), Fr::from(20)),
+            Err(PoseidonError::KeyNotFound)
+        );
+        assert_eq!(tree.delete(Fr::from(2)), Err(PoseidonError::KeyNotFound));
+    }
+}
@@ -0,0 +1,232 @@
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::bn256::Fr;
+use params::{PoseidonField, PoseidonParams};
+use templates::permute_in_place;
+
+/// Sponge mode, either absorbing inputs or squeezing outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Absorbing,
+    Squeezing,
+}
+
+/// Sponge construction on top of the circomlib permutation.
+///
+/// The state is laid out as `[capacity | rate]`, so the first capacity element is the
+/// `initial_state` slot of `PoseidonEx` and the rate elements are its inputs.
+///
+/// Domain separation: the first capacity element starts as the domain tag (`2^64` by
+/// default, the variable-input-length tag of the Poseidon paper), the others start as zero.
+/// This keeps the sponge apart from the fixed-length hash, which starts with zero.
+///
+/// Padding: inputs are added into the rate part and the state is permuted whenever the rate
+/// is full. Before the first squeeze, `1` is added right after the last input and the state
+/// is permuted (10* padding), so inputs of different lengths never share a padded form.
+/// Outputs are read from the rate part, permuting again whenever it is exhausted.
+#[derive(Clone, Debug)]
+pub struct Sponge<F: 'static = Fr> {
+    params: &'static PoseidonParams<F>,
+    state: Vec<F>,
+    rate: usize,
+    capacity: usize,
+    pos: usize,
+    mode: Mode,
+}
+
+impl<F: PoseidonField> Sponge<F> {
+    /// Creates a sponge with the default domain tag.
+    pub fn new(rate: usize, capacity: usize) -> Result<Self, PoseidonError> {
+        let domain = F::from(1 << 32) * F::from(1 << 32);
+        Self::new_with_domain(rate, capacity, domain)
+    }
+
+    /// Creates a sponge whose first capacity element starts as the given domain tag.
+    pub fn new_with_domain(rate: usize, capacity: usize, domain: F) -> Result<Self, PoseidonError> {
+        if rate == 0 || capacity == 0 {
+            return Err(PoseidonError::UnsupportedArity(
+                (rate + capacity).saturating_sub(1),
+            ));
+        }
+
+        let params = F::params(rate + capacity)?;
+        let mut state = vec![F::ZERO; rate + capacity];
+        state[0] = domain;
+
+        Ok(Self {
+            params,
+            state,
+            rate,
+            capacity,
+            pos: 0,
+            mode: Mode::Absorbing,
+        })
+    }
+
+    /// Absorbs the given elements, permuting whenever the rate is full.
+    pub fn absorb(&mut self, inputs: &[F]) {
+        if self.mode == Mode::Squeezing {
+            self.mode = Mode::Absorbing;
+            self.pos = 0;
+        }
+
+        for item in inputs {
+            if self.pos == self.rate {
+                self.permute();
+                self.pos = 0;
+            }
+
+            self.state[self.capacity + self.pos] += item;
+            self.pos += 1;
+        }
+    }
+
+    /// Squeezes the given number of elements, padding the absorbed inputs first.
+    pub fn squeeze(&mut self, n: usize) -> Vec<F> {
+        if self.mode == Mode::Absorbing {
+            if self.pos == self.rate {
+                self.permute();
+                self.pos = 0;
+            }
+
+            self.state[self.capacity + self.pos] += F::ONE;
+            self.permute();
+            self.mode = Mode::Squeezing;
+            self.pos = 0;
+        }
+
+        let mut outputs = Vec::with_capacity(n);
+
+        for _ in 0..n {
+            if self.pos == self.rate {
+                self.permute();
+                self.pos = 0;
+            }
+
+            outputs.push(self.state[self.capacity + self.pos]);
+            self.pos += 1;
+        }
+
+        outputs
+    }
+
+    /// Applies the permutation to the whole state.
+    fn permute(&mut self) {
+        let mut buffer = vec![F::ZERO; self.state.len()];
+        permute_in_place(self.params, &mut self.state, &mut buffer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use templates::Poseidon;
+
+    #[test]
+    fn test_sponge_padding() {
+        // One input with rate 2 is padded to [input, 1] and permuted once.
+        let domain = Fr::from(3);
+        let mut sponge = Sponge::new_with_domain(2, 1, domain).unwrap();
+        sponge.absorb(&[Fr::from(5)]);
+        let output = sponge.squeeze(2);
+
+        let poseidon = Poseidon::new([Fr::from(5), Fr::one()].to_vec());
+        let expected = poseidon.poseidon_ex_multi(domain, 3).unwrap();
+
+        assert_eq!(output, expected[1..].to_vec());
+
+        // A full rate is padded with an extra block.
+        let mut sponge = Sponge::new_with_domain(2, 1, domain).unwrap();
+        sponge.absorb(&[Fr::from(5), Fr::from(77)]);
+        let output = sponge.squeeze(1);
+
+        let poseidon = Poseidon::new([Fr::from(5), Fr::from(77)].to_vec());
+        let state = poseidon.poseidon_ex_multi(domain, 3).unwrap();
+        let poseidon = Poseidon::new([state[1] + Fr::one(), state[2]].to_vec());
+        let expected = poseidon.poseidon_ex_multi(state[0], 3).unwrap();
+
+        assert_eq!(output[0], expected[1]);
+    }
+
+    #[test]
+    fn test_sponge_lengths() {
+        // Trailing zeros and different lengths give different outputs.
+        let mut outputs = Vec::new();
+        for len in 0..6 {
+            let mut sponge = Sponge::new(2, 1).unwrap();
+            sponge.absorb(&vec![Fr::zero(); len]);
+            outputs.push(sponge.squeeze(1)[0]);
+        }
+
+        for i in 0..outputs.len() {
+            for j in i + 1..outputs.len() {
+                assert_ne!(outputs[i], outputs[j]);
+            }
+        }
+
+        // Default domain separates the sponge from the fixed-length hash.
+        let mut sponge = Sponge::new(2, 1).unwrap();
+        sponge.absorb(&[Fr::from(5)]);
+        let poseidon = Poseidon::new([Fr::from(5), Fr::one()].to_vec());
+        assert_ne!(
+            sponge.squeeze(1)[0],
+            poseidon.poseidon_ex(Fr::zero(), 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sponge_incremental() {
+        let inputs: Vec<Fr> = (0..11).map(Fr::from).collect();
+
+        for (rate, capacity) in [(1, 1), (2, 1), (4, 1), (3, 2), (15, 2)] {
+            let mut sponge = Sponge::new(rate, capacity).unwrap();
+            sponge.absorb(&inputs);
+            let expected = sponge.squeeze(9);
+
+            // Absorbing in chunks and squeezing in chunks gives the same outputs.
+            let mut sponge = Sponge::new(rate, capacity).unwrap();
+            for chunk in inputs.chunks(3) {
+                sponge.absorb(chunk);
+            }
+            let mut output = sponge.squeeze(4);
+            output.extend(sponge.squeeze(5));
+
+            assert_eq!(expected, output);
+        }
+    }
+
+    #[test]
+    fn test_sponge_domain() {
+        let mut sponge_a = Sponge::new_with_domain(2, 1, Fr::from(1)).unwrap();
+        let mut sponge_b = Sponge::new_with_domain(2, 1, Fr::from(2)).unwrap();
+        sponge_a.absorb(&[Fr::from(5)]);
+        sponge_b.absorb(&[Fr::from(5)]);
+
+        assert_ne!(sponge_a.squeeze(1), sponge_b.squeeze(1));
+    }
+
+    #[test]
+    fn test_sponge_duplex() {
+        // Absorbing after squeezing starts a new block and pads again.
+        let mut sponge = Sponge::new(2, 1).unwrap();
+        sponge.absorb(&[Fr::from(1)]);
+        let first = sponge.squeeze(1);
+        sponge.absorb(&[Fr::from(2)]);
+        let second = sponge.squeeze(1);
+
+        let mut other = Sponge::new(2, 1).unwrap();
+        other.absorb(&[Fr::from(1), Fr::from(2)]);
+
+        assert_ne!(first, second);
+        assert_ne!(second, other.squeeze(1));
+    }
+
+    #[test]
+    fn test_sponge_invalid_width() {
+        let error = Sponge::<Fr>::new(16, 2).unwrap_err();
+        assert_eq!(error, PoseidonError::UnsupportedArity(17));
+
+        let error = Sponge::<Fr>::new(2, 0).unwrap_err();
+        assert_eq!(error, PoseidonError::UnsupportedArity(1));
+    }
+}
@@ -0,0 +1,971 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use super::*;
+use error::PoseidonError;
+use halo2::halo2curves::{bn256::Fr, ff::PrimeField};
+use merkle::{check_index, hash_pair, path, set_path, zeros, MerkleProof, TreeNodes, MAX_DEPTH};
+use params::PoseidonField;
+use utils::modulus;
+
+/// Storage of the nodes of a binary Merkle tree, addressed by level (0 for the leaves) and
+/// position in the level.
+pub trait NodeStore<F> {
+    /// Returns the node at the level and position, None if it was never set.
+    fn get(&self, level: usize, index: usize) -> Result<Option<F>, PoseidonError>;
+
+    /// Sets the node at the level and position.
+    fn put(&mut self, level: usize, index: usize, node: F) -> Result<(), PoseidonError>;
+
+    /// Ends an update: the nodes put since the last commit are kept all together or not at all.
+    fn commit(&mut self) -> Result<(), PoseidonError> {
+        Ok(())
+    }
+
+    /// Drops the nodes put since the last commit, after an update failed.
+    fn abort(&mut self) {}
+
+    /// Writes the pending changes to the underlying storage.
+    fn flush(&mut self) -> Result<(), PoseidonError> {
+        Ok(())
+    }
+
+    /// Drops the storage taken by nodes that were set again since.
+    fn compact(&mut self) -> Result<(), PoseidonError> {
+        Ok(())
+    }
+}
+
+/// Nodes kept in memory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStore<F = Fr> {
+    nodes: HashMap<(usize, usize), F>,
+}
+
+impl<F> MemoryStore<F> {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+        }
+    }
+}
+
+impl<F: Copy> NodeStore<F> for MemoryStore<F> {
+    fn get(&self, level: usize, index: usize) -> Result<Option<F>, PoseidonError> {
+        Ok(self.nodes.get(&(level, index)).copied())
+    }
+
+    fn put(&mut self, level: usize, index: usize, node: F) -> Result<(), PoseidonError> {
+        self.nodes.insert((level, index), node);
+        Ok(())
+    }
+}
+
+/// Size of the level and position before every node of the log.
+const RECORD_HEADER_SIZE: usize = 12;
+
+/// Level of the record closing a commit, whose position is the number of records it commits.
+const COMMIT_LEVEL: u32 = u32::MAX;
+
+/// Size of the header of the index, holding the generation of the log it indexes and the length
+/// of the log indexed so far.
+const INDEX_HEADER_SIZE: u64 = 16;
+
+/// Reads exactly `buf.len()` bytes at the offset, without the cursor shared by the readers of
+/// the file.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut read = 0;
+        while read < buf.len() {
+            match std::os::windows::fs::FileExt::seek_read(
+                file,
+                &mut buf[read..],
+                offset + read as u64,
+            )? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the whole buffer at the offset.
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut written = 0;
+        while written < buf.len() {
+            written += std::os::windows::fs::FileExt::seek_write(
+                file,
+                &buf[written..],
+                offset + written as u64,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Nodes kept in a directory.
+///
+/// `nodes.log` holds a header with the depth, the representation size, the modulus, the zero
+/// leaf and a generation, then an append-only log of (level, position, node) records. The
+/// nodes put since the last `commit` are written together with a closing commit record.
+///
+/// `nodes.idx` holds, for every node of a tree of the given depth, one plus the offset of its
+/// last committed record, or zero if it was never set. It is a sparse file: slots of nodes
+/// never set take no space on file systems with sparse files. Its header tells how much of the
+/// log it indexes, so opening only replays the records after it, and truncates a commit that
+/// was not closed: an interrupted write leaves the nodes of the previous commit.
+///
+/// `compact` rewrites the log with the last record of every node, in a new generation.
+#[derive(Debug)]
+pub struct FileStore<F = Fr> {
+    path: PathBuf,
+    log: File,
+    index: File,
+    depth: usize,
+    generation: u64,
+    pending: Vec<(usize, usize, F)>,
+    log_len: u64,
+}
+
+impl<F: PrimeField> FileStore<F> {
+    /// Opens the store in the directory, creating it if needed. A store created for another
+    /// depth, field or zero leaf is rejected.
+    pub fn open(path: impl AsRef<Path>, depth: usize, zero: F) -> Result<Self, PoseidonError> {
+        if !(1..=MAX_DEPTH).contains(&depth) {
+            return Err(PoseidonError::InvalidDepth(depth));
+        }
+
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        // A compaction that was not renamed into place is dropped.
+        fs::remove_file(path.join("nodes.log.tmp")).ok();
+
+        let mut log = Self::open_log(path)?;
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join("nodes.idx"))?;
+
+        let identity = Self::identity(depth, zero);
+        let header_len = (identity.len() + 8) as u64;
+        let len = log.metadata()?.len();
+        let generation = if len == 0 {
+            log.write_all(&[identity.as_slice(), &0u64.to_le_bytes()].concat())?;
+            log.sync_data()?;
+            0
+        } else {
+            let mut stored = vec![0; header_len as usize];
+            log.read_exact(&mut stored).map_err(|_| corrupted())?;
+            Self::check_identity(&stored[..identity.len()], &identity, depth)?;
+            u64::from_le_bytes(
+                stored[identity.len()..]
+                    .try_into()
+                    .expect("Invalid parameters!"),
+            )
+        };
+
+        let mut store = Self {
+            path: path.to_path_buf(),
+            log,
+            index,
+            depth,
+            generation,
+            pending: Vec::new(),
+            log_len: header_len,
+        };
+
+        // An index of another generation, e.g. after an interrupted compaction, is rebuilt.
+        let mut header = [0; INDEX_HEADER_SIZE as usize];
+        let indexed = match read_at(&store.index, &mut header, 0) {
+            Ok(())
+                if u64::from_le_bytes(header[..8].try_into().expect("Invalid parameters!"))
+                    == generation =>
+            {
+                u64::from_le_bytes(header[8..].try_into().expect("Invalid parameters!"))
+            }
+            _ => {
+                store.reset_index()?;
+                header_len
+            }
+        };
+        if indexed < header_len || indexed > len.max(header_len) {
+            return Err(corrupted());
+        }
+
+        store.log_len = indexed;
+        store.replay()?;
+        Ok(store)
+    }
+
+    /// Returns the depth of the tree the store was created for.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Opens the log of the directory for reading and appending.
+    fn open_log(path: &Path) -> Result<File, PoseidonError> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path.join("nodes.log"))?)
+    }
+
+    /// Returns the start of the log header, which a reopened store must match.
+    fn identity(depth: usize, zero: F) -> Vec<u8> {
+        let repr_size = Self::repr_size();
+        let mut modulus = modulus::<F>().to_bytes_le();
+        modulus.resize(repr_size, 0);
+        [
+            (depth as u64).to_le_bytes().to_vec(),
+            (repr_size as u64).to_le_bytes().to_vec(),
+            modulus,
+            zero.to_repr().as_ref().to_vec(),
+        ]
+        .concat()
+    }
+
+    /// Checks the stored start of the log header against the expected one.
+    fn check_identity(stored: &[u8], expected: &[u8], depth: usize) -> Result<(), PoseidonError> {
+        let depth_stored = u64::from_le_bytes(stored[..8].try_into().expect("Invalid parameters!"));
+        if depth_stored != depth as u64 {
+            return Err(PoseidonError::Storage(format!(
+                "store has depth {}, not {}",
+                depth_stored, depth
+            )));
+        }
+
+        let repr_size = Self::repr_size();
+        if stored[8..16 + repr_size] != expected[8..16 + repr_size] {
+            return Err(PoseidonError::Storage(
+                "store is for another field".to_string(),
+            ));
+        }
+        if stored != expected {
+            return Err(PoseidonError::Storage(
+                "store has another zero leaf".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the representation of the field.
+    fn repr_size() -> usize {
+        F::Repr::default().as_ref().len()
+    }
+
+    /// Returns the size of a record of the log.
+    fn record_size() -> usize {
+        RECORD_HEADER_SIZE + Self::repr_size()
+    }
+
+    /// Returns the size of the header of the log.
+    fn header_len(&self) -> u64 {
+        (16 + 2 * Self::repr_size() + 8) as u64
+    }
+
+    /// Empties the index, for the whole log to be replayed.
+    fn reset_index(&mut self) -> Result<(), PoseidonError> {
+        self.index.set_len(0)?;
+        self.write_index_header(self.header_len())
+    }
+
+    /// Records that the index holds the log up to the given length.
+    fn write_index_header(&self, indexed: u64) -> Result<(), PoseidonError> {
+        let header = [self.generation.to_le_bytes(), indexed.to_le_bytes()].concat();
+        write_at(&self.index, &header, 0)?;
+        Ok(())
+    }
+
+    /// Indexes the committed records of the log after its indexed length, and drops the
+    /// records after the last commit.
+    fn replay(&mut self) -> Result<(), PoseidonError> {
+        let len = self.log.metadata()?.len();
+        let record_size = Self::record_size() as u64;
+        let mut reader = BufReader::new(&self.log);
+        reader.seek(SeekFrom::Start(self.log_len))?;
+
+        let mut record = vec![0; record_size as usize];
+        let mut batch = Vec::new();
+        let mut offset = self.log_len;
+        while offset + record_size <= len {
+            reader.read_exact(&mut record)?;
+            let (level, index) = parse_record_header(&record);
+
+            if level != COMMIT_LEVEL {
+                batch.push((level as usize, index as usize, offset));
+            } else if index == batch.len() as u64 {
+                for (level, index, offset) in batch.drain(..) {
+                    self.write_slot(level, index, offset)?;
+                }
+                self.log_len = offset + record_size;
+            } else {
+                break;
+            }
+            offset += record_size;
+        }
+
+        if self.log_len < len {
+            self.log.set_len(self.log_len)?;
+            self.log.sync_data()?;
+        }
+        self.write_index_header(self.log_len)
+    }
+
+    /// Checks that the node is in a tree of the depth of the store.
+    fn check(&self, level: usize, index: usize) -> Result<(), PoseidonError> {
+        if level > self.depth || index >> (self.depth - level) != 0 {
+            return Err(PoseidonError::Storage(format!(
+                "node {} of level {} is out of range",
+                index, level
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of the index slot of the node.
+    fn slot(&self, level: usize, index: usize) -> u64 {
+        let id = (1 << (self.depth - level)) - 1 + index;
+        INDEX_HEADER_SIZE + 8 * id as u64
+    }
+
+    /// Returns the offset of the last committed record of the node.
+    fn read_slot(&self, level: usize, index: usize) -> Result<Option<u64>, PoseidonError> {
+        let mut offset = [0; 8];
+        match read_at(&self.index, &mut offset, self.slot(level, index)) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        Ok(u64::from_le_bytes(offset).checked_sub(1))
+    }
+
+    /// Points the index slot of the node to its record.
+    fn write_slot(&self, level: usize, index: usize, offset: u64) -> Result<(), PoseidonError> {
+        write_at(
+            &self.index,
+            &(offset + 1).to_le_bytes(),
+            self.slot(level, index),
+        )?;
+        Ok(())
+    }
+}
+
+/// Returns the level and position of a record.
+fn parse_record_header(record: &[u8]) -> (u32, u64) {
+    let level = u32::from_le_bytes(record[..4].try_into().expect("Invalid parameters!"));
+    let index = u64::from_le_bytes(record[4..12].try_into().expect("Invalid parameters!"));
+    (level, index)
+}
+
+/// Returns the error of a log that cannot be read back.
+fn corrupted() -> PoseidonError {
+    PoseidonError::Storage("corrupted node log".to_string())
+}
+
+impl<F: PrimeField> NodeStore<F> for FileStore<F> {
+    fn get(&self, level: usize, index: usize) -> Result<Option<F>, PoseidonError> {
+        self.check(level, index)?;
+        if let Some((.., node)) = self
+            .pending
+            .iter()
+            .rev()
+            .find(|(l, i, _)| (*l, *i) == (level, index))
+        {
+            return Ok(Some(*node));
+        }
+        let offset = match self.read_slot(level, index)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let mut record = vec![0; Self::record_size()];
+        read_at(&self.log, &mut record, offset).map_err(|_| corrupted())?;
+        if parse_record_header(&record) != (level as u32, index as u64) {
+            return Err(corrupted());
+        }
+
+        let mut repr = F::Repr::default();
+        repr.as_mut().copy_from_slice(&record[RECORD_HEADER_SIZE..]);
+        Option::from(F::from_repr(repr))
+            .map(Some)
+            .ok_or_else(corrupted)
+    }
+
+    fn put(&mut self, level: usize, index: usize, node: F) -> Result<(), PoseidonError> {
+        self.check(level, index)?;
+        self.pending.push((level, index, node));
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), PoseidonError> {
+        let pending = mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut records = Vec::with_capacity((pending.len() + 1) * Self::record_size());
+        for (level, index, node) in pending.iter() {
+            records.extend_from_slice(&(*level as u32).to_le_bytes());
+            records.extend_from_slice(&(*index as u64).to_le_bytes());
+            records.extend_from_slice(node.to_repr().as_ref());
+        }
+        records.extend_from_slice(&COMMIT_LEVEL.to_le_bytes());
+        records.extend_from_slice(&(pending.len() as u64).to_le_bytes());
+        records.resize(records.len() + Self::repr_size(), 0);
+
+        // A failed write is cut off, the next commit appends after the previous one.
+        if let Err(error) = self.log.write_all(&records) {
+            self.log.set_len(self.log_len).ok();
+            return Err(error.into());
+        }
+
+        // The index follows the log, a commit it misses is indexed again on opening.
+        let record_size = Self::record_size() as u64;
+        for (i, (level, index, _)) in pending.into_iter().enumerate() {
+            self.write_slot(level, index, self.log_len + i as u64 * record_size)?;
+        }
+        self.log_len += records.len() as u64;
+        self.write_index_header(self.log_len)
+    }
+
+    fn abort(&mut self) {
+        self.pending.clear();
+    }
+
+    fn flush(&mut self) -> Result<(), PoseidonError> {
+        self.log.sync_data()?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), PoseidonError> {
+        self.pending.clear();
+        let record_size = Self::record_size();
+        let header_len = self.header_len();
+
+        // The records the index points to, in one commit of the next generation.
+        let mut header = vec![0; header_len as usize - 8];
+        read_at(&self.log, &mut header, 0)?;
+        let tmp = self.path.join("nodes.log.tmp");
+        let mut compacted = io::BufWriter::new(File::create(&tmp)?);
+        compacted.write_all(&header)?;
+        compacted.write_all(&(self.generation + 1).to_le_bytes())?;
+
+        let mut reader = BufReader::new(&self.log);
+        reader.seek(SeekFrom::Start(header_len))?;
+        let mut record = vec![0; record_size];
+        let mut count = 0u64;
+        for offset in (header_len..self.log_len).step_by(record_size) {
+            reader.read_exact(&mut record)?;
+            let (level, index) = parse_record_header(&record);
+            if level != COMMIT_LEVEL
+                && self.read_slot(level as usize, index as usize)? == Some(offset)
+            {
+                compacted.write_all(&record)?;
+                count += 1;
+            }
+        }
+        record.fill(0);
+        record[..4].copy_from_slice(&COMMIT_LEVEL.to_le_bytes());
+        record[4..12].copy_from_slice(&count.to_le_bytes());
+        compacted.write_all(&record)?;
+        compacted
+            .into_inner()
+            .map_err(|error| error.into_error())?
+            .sync_all()?;
+
+        // Once renamed, the log of the next generation is the store, an index of the previous
+        // one is rebuilt on opening.
+        fs::rename(&tmp, self.path.join("nodes.log"))?;
+        self.log = Self::open_log(&self.path)?;
+        self.generation += 1;
+        self.log_len = header_len;
+        self.reset_index()?;
+        self.replay()
+    }
+}
+
+/// Fixed-depth binary Merkle tree with the nodes of `MerkleTree` kept in a `NodeStore`. A tree
+/// opened on a store that already holds nodes resumes from them without hashing.
+#[derive(Clone, Debug)]
+pub struct PersistentMerkleTree<F = Fr, S = MemoryStore<F>> {
+    store: S,
+    depth: usize,
+    zeros: Vec<F>,
+    root: F,
+    n_leaves: usize,
+}
+
+impl<F: PoseidonField, S: NodeStore<F>> PersistentMerkleTree<F, S> {
+    /// Opens a tree of the given depth whose empty leaves are `zero` on the store.
+    pub fn new(store: S, depth: usize, zero: F) -> Result<Self, PoseidonError> {
+        if !(1..=MAX_DEPTH).contains(&depth) {
+            return Err(PoseidonError::InvalidDepth(depth));
+        }
+
+        // Leaves are filled from the left, so the set ones are a prefix.
+        let (mut low, mut high) = (0, 1 << depth);
+        while low < high {
+            let mid = (low + high) / 2;
+            if store.get(0, mid)?.is_some() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let zeros = zeros(zero, depth);
+        let root = store.get(depth, 0)?.unwrap_or(zeros[depth]);
+        Ok(Self {
+            store,
+            depth,
+            zeros,
+            root,
+            n_leaves: low,
+        })
+    }
+
+    /// Returns the depth of the tree.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the maximum number of leaves.
+    pub fn capacity(&self) -> usize {
+        1 << self.depth
+    }
+
+    /// Returns the number of inserted leaves.
+    pub fn len(&self) -> usize {
+        self.n_leaves
+    }
+
+    /// Checks whether no leaf was inserted.
+    pub fn is_empty(&self) -> bool {
+        self.n_leaves == 0
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> F {
+        self.root
+    }
+
+    /// Returns the store of the tree.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns the leaf at the given index.
+    pub fn leaf(&self, index: usize) -> Result<F, PoseidonError> {
+        check_index(index, self.n_leaves)?;
+        self.get_node(0, index)
+    }
+
+    /// Appends a leaf and returns its index.
+    pub fn insert(&mut self, leaf: F) -> Result<usize, PoseidonError> {
+        let index = self.n_leaves;
+        if index == self.capacity() {
+            return Err(PoseidonError::TreeFull);
+        }
+
+        self.set(index, leaf)?;
+        self.n_leaves += 1;
+        Ok(index)
+    }
+
+    /// Replaces the leaf at the given index.
+    pub fn update(&mut self, index: usize, leaf: F) -> Result<(), PoseidonError> {
+        check_index(index, self.n_leaves)?;
+        self.set(index, leaf)
+    }
+
+    /// Replaces the leaf at the given index with the zero leaf, keeping the other indices.
+    pub fn delete(&mut self, index: usize) -> Result<(), PoseidonError> {
+        self.update(index, self.zeros[0])
+    }
+
+    /// Returns the inclusion proof of the leaf at the given index.
+    pub fn proof(&self, index: usize) -> Result<MerkleProof<F>, PoseidonError> {
+        check_index(index, self.n_leaves)?;
+        let (siblings, path_indices) = path(self, index)?;
+
+        Ok(MerkleProof {
+            root: self.root,
+            leaf: self.get_node(0, index)?,
+            siblings: siblings.concat(),
+            path_indices,
+        })
+    }
+
+    /// Writes the pending changes of the store.
+    pub fn flush(&mut self) -> Result<(), PoseidonError> {
+        self.store.flush()
+    }
+
+    /// Drops the storage taken by the previous versions of the nodes.
+    pub fn compact(&mut self) -> Result<(), PoseidonError> {
+        self.store.compact()
+    }
+
+    /// Sets the leaf at an index up to the number of leaves and updates its path, in one commit
+    /// of the store, which is aborted if the update fails.
+    fn set(&mut self, index: usize, leaf: F) -> Result<(), PoseidonError> {
+        let result = set_path(self, index, leaf).and_then(|root| {
+            self.store.commit()?;
+            Ok(root)
+        });
+
+        match result {
+            Ok(root) => {
+                self.root = root;
+                Ok(())
+            }
+            Err(error) => {
+                self.store.abort();
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<F: PoseidonField, S: NodeStore<F>> TreeNodes<F> for PersistentMerkleTree<F, S> {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn get_node(&self, level: usize, position: usize) -> Result<F, PoseidonError> {
+        Ok(self
+            .store
+            .get(level, position)?
+            .unwrap_or(self.zeros[level]))
+    }
+
+    fn put_node(&mut self, level: usize, position: usize, node: F) -> Result<(), PoseidonError> {
+        self.store.put(level, position, node)
+    }
+
+    fn hash_children(&self, children: &[F]) -> F {
+        hash_pair(children[0], children[1])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use halo2::halo2curves::pasta;
+    use merkle::MerkleTree;
+    use std::{env, process};
+
+    /// Returns an empty directory for the test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("cirpos-{}-{}", name, process::id()));
+        fs::remove_dir_all(&path).ok();
+        path
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut tree = PersistentMerkleTree::new(MemoryStore::new(), 4, Fr::zero()).unwrap();
+        let mut expected = MerkleTree::new(4, Fr::zero()).unwrap();
+        assert_eq!(tree.root(), expected.root());
+
+        for i in 0..11 {
+            assert_eq!(tree.insert(Fr::from(i)), expected.insert(Fr::from(i)));
+            assert_eq!(tree.root(), expected.root());
+        }
+        tree.update(3, Fr::from(33)).unwrap();
+        expected.update(3, Fr::from(33)).unwrap();
+        tree.delete(10).unwrap();
+        expected.delete(10).unwrap();
+
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.len(), 11);
+        assert_eq!(tree.leaf(3), Ok(Fr::from(33)));
+        for i in 0..11 {
+            assert_eq!(tree.proof(i), expected.proof(i));
+        }
+        assert_eq!(
+            tree.proof(11),
+            Err(PoseidonError::IndexOutOfRange { index: 11, len: 11 })
+        );
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = test_dir("file-store");
+        let mut expected = MerkleTree::new(5, Fr::zero()).unwrap();
+
+        {
+            let store = FileStore::open(&path, 5, Fr::zero()).unwrap();
+            let mut tree = PersistentMerkleTree::new(store, 5, Fr::zero()).unwrap();
+            for i in 0..7 {
+                tree.insert(Fr::from(i)).unwrap();
+                expected.insert(Fr::from(i)).unwrap();
+            }
+            tree.update(2, Fr::from(22)).unwrap();
+            expected.update(2, Fr::from(22)).unwrap();
+            tree.flush().unwrap();
+        }
+
+        // Reopening resumes from the stored nodes.
+        let store = FileStore::open(&path, 5, Fr::zero()).unwrap();
+        let mut tree = PersistentMerkleTree::new(store, 5, Fr::zero()).unwrap();
+        assert_eq!(tree.len(), 7);
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.proof(2), expected.proof(2));
+
+        for i in 7..12 {
+            tree.insert(Fr::from(i)).unwrap();
+            expected.insert(Fr::from(i)).unwrap();
+        }
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.proof(9), expected.proof(9));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_interrupted() {
+        let path = test_dir("file-store-interrupted");
+        let mut expected = MerkleTree::new(4, Fr::zero()).unwrap();
+
+        {
+            let store = FileStore::open(&path, 4, Fr::zero()).unwrap();
+            let mut tree = PersistentMerkleTree::new(store, 4, Fr::zero()).unwrap();
+            for i in 0..5 {
+                tree.insert(Fr::from(i)).unwrap();
+                expected.insert(Fr::from(i)).unwrap();
+            }
+        }
+        let committed = fs::metadata(path.join("nodes.log")).unwrap().len();
+
+        // An insertion cut off before its commit record: the new leaf, its parent and part of
+        // the next record.
+        let mut store = FileStore::open(&path, 4, Fr::zero()).unwrap();
+        store.put(0, 5, Fr::from(5)).unwrap();
+        store.put(1, 2, Fr::from(6)).unwrap();
+        let mut records = Vec::new();
+        for (level, index, node) in store.pending.iter() {
+            records.extend_from_slice(&(*level as u32).to_le_bytes());
+            records.extend_from_slice(&(*index as u64).to_le_bytes());
+            records.extend_from_slice(node.to_repr().as_ref());
+        }
+        records.extend_from_slice(&[0; RECORD_HEADER_SIZE]);
+        store.log.write_all(&records).unwrap();
+        drop(store);
+
+        // Reopening drops the uncommitted records and keeps the path of leaf 4 whole.
+        let store = FileStore::open(&path, 4, Fr::zero()).unwrap();
+        assert_eq!(
+            fs::metadata(path.join("nodes.log")).unwrap().len(),
+            committed
+        );
+        let mut tree = PersistentMerkleTree::new(store, 4, Fr::zero()).unwrap();
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.proof(4), expected.proof(4));
+
+        tree.insert(Fr::from(5)).unwrap();
+        expected.insert(Fr::from(5)).unwrap();
+        drop(tree);
+
+        let store = FileStore::open(&path, 4, Fr::zero()).unwrap();
+        let tree = PersistentMerkleTree::new(store, 4, Fr::zero()).unwrap();
+        assert_eq!(tree.root(), expected.root());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_size() {
+        let path = test_dir("file-store-size");
+
+        // The log grows with the nodes set, not with the capacity of the tree.
+        let store = FileStore::open(&path, 32, Fr::zero()).unwrap();
+        let mut tree = PersistentMerkleTree::new(store, 32, Fr::zero()).unwrap();
+        for i in 0..3 {
+            tree.insert(Fr::from(i)).unwrap();
+        }
+        tree.update(1, Fr::from(7)).unwrap();
+
+        let header = 16 + 2 * 32 + 8;
+        let record = RECORD_HEADER_SIZE as u64 + 32;
+        let len = fs::metadata(path.join("nodes.log")).unwrap().len();
+        assert_eq!(len, header + 4 * 34 * record);
+
+        // The index spans the whole tree but only the slots set take space.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let index = fs::metadata(path.join("nodes.idx")).unwrap();
+            assert!(index.len() > 1 << 33);
+            assert!(index.blocks() * 512 < 1 << 20);
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// File store failing to read a chosen node.
+    struct FailingStore {
+        inner: FileStore,
+        fail: Option<(usize, usize)>,
+    }
+
+    impl NodeStore<Fr> for FailingStore {
+        fn get(&self, level: usize, index: usize) -> Result<Option<Fr>, PoseidonError> {
+            if self.fail == Some((level, index)) {
+                return Err(PoseidonError::Storage("failed read".to_string()));
+            }
+            self.inner.get(level, index)
+        }
+
+        fn put(&mut self, level: usize, index: usize, node: Fr) -> Result<(), PoseidonError> {
+            self.inner.put(level, index, node)
+        }
+
+        fn commit(&mut self) -> Result<(), PoseidonError> {
+            self.inner.commit()
+        }
+
+        fn abort(&mut self) {
+            self.inner.abort()
+        }
+    }
+
+    #[test]
+    fn test_file_store_failed_set() {
+        let path = test_dir("file-store-failed-set");
+        let mut expected = MerkleTree::new(4, Fr::zero()).unwrap();
+
+        let inner = FileStore::open(&path, 4, Fr::zero()).unwrap();
+        let store = FailingStore { inner, fail: None };
+        let mut tree = PersistentMerkleTree::new(store, 4, Fr::zero()).unwrap();
+        for i in 0..3 {
+            tree.insert(Fr::from(i)).unwrap();
+            expected.insert(Fr::from(i)).unwrap();
+        }
+
+        // Inserting leaf 3 fails after putting it, on reading the sibling of its parent.
+        tree.store.fail = Some((1, 0));
+        assert!(tree.insert(Fr::from(3)).is_err());
+        tree.store.fail = None;
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.root(), expected.root());
+
+        // The next commit does not carry the nodes of the failed insertion.
+        tree.update(1, Fr::from(11)).unwrap();
+        expected.update(1, Fr::from(11)).unwrap();
+        assert_eq!(tree.store().get(0, 3), Ok(None));
+        assert_eq!(tree.root(), expected.root());
+        drop(tree);
+
+        let store = FileStore::open(&path, 4, Fr::zero()).unwrap();
+        let tree = PersistentMerkleTree::new(store, 4, Fr::zero()).unwrap();
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.root(), expected.root());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_compact() {
+        let path = test_dir("file-store-compact");
+        let mut expected = MerkleTree::new(6, Fr::zero()).unwrap();
+
+        let store = FileStore::open(&path, 6, Fr::zero()).unwrap();
+        let mut tree = PersistentMerkleTree::new(store, 6, Fr::zero()).unwrap();
+        for i in 0..10 {
+            tree.insert(Fr::from(i)).unwrap();
+            expected.insert(Fr::from(i)).unwrap();
+        }
+        for i in 0..50 {
+            tree.update(i % 10, Fr::from(100 + i as u64)).unwrap();
+            expected.update(i % 10, Fr::from(100 + i as u64)).unwrap();
+        }
+
+        // Compaction keeps the last version of every node.
+        let len = fs::metadata(path.join("nodes.log")).unwrap().len();
+        tree.compact().unwrap();
+        assert!(fs::metadata(path.join("nodes.log")).unwrap().len() < len / 10);
+        assert_eq!(tree.root(), expected.root());
+        for i in 0..10 {
+            assert_eq!(tree.proof(i), expected.proof(i));
+        }
+
+        tree.insert(Fr::from(10)).unwrap();
+        expected.insert(Fr::from(10)).unwrap();
+        drop(tree);
+
+        let store = FileStore::open(&path, 6, Fr::zero()).unwrap();
+        let tree = PersistentMerkleTree::new(store, 6, Fr::zero()).unwrap();
+        assert_eq!(tree.len(), 11);
+        assert_eq!(tree.root(), expected.root());
+        drop(tree);
+
+        // An index of another generation is rebuilt from the log.
+        let index = OpenOptions::new()
+            .write(true)
+            .open(path.join("nodes.idx"))
+            .unwrap();
+        write_at(&index, &7u64.to_le_bytes(), 0).unwrap();
+        drop(index);
+        let store = FileStore::open(&path, 6, Fr::zero()).unwrap();
+        let tree = PersistentMerkleTree::new(store, 6, Fr::zero()).unwrap();
+        assert_eq!(tree.len(), 11);
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.proof(10), expected.proof(10));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_errors() {
+        let path = test_dir("file-store-errors");
+
+        let mut store = FileStore::open(&path, 3, Fr::zero()).unwrap();
+        assert_eq!(store.get(0, 7), Ok(None));
+        assert!(store.put(0, 8, Fr::one()).is_err());
+        assert!(store.put(4, 0, Fr::one()).is_err());
+        drop(store);
+
+        // The depth, field and zero leaf of the store must match.
+        for error in [
+            FileStore::open(&path, 4, Fr::zero()).unwrap_err(),
+            FileStore::open(&path, 3, Fr::one()).unwrap_err(),
+            FileStore::open(&path, 3, pasta::Fp::zero()).unwrap_err(),
+        ] {
+            assert!(matches!(error, PoseidonError::Storage(_)));
+        }
+        assert!(FileStore::open(&path, 3, Fr::zero()).is_ok());
+        assert_eq!(
+            FileStore::open(&path, 0, Fr::zero()).unwrap_err(),
+            PoseidonError::InvalidDepth(0)
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,210 +1,254 @@
 use std::ops::{Index, IndexMut};
 
 use super::*;
-use halo2::halo2curves::bn256::Fr;
-use utils::{poseidon_c, poseidon_m, poseidon_p, poseidon_s};
+use error::PoseidonError;
+use halo2::halo2curves::{bn256::Fr, ff::PrimeField};
+use params::{PoseidonField, PoseidonParams};
 
 /// Constructs objects.
 #[derive(Clone, Debug)]
-pub struct Poseidon {
-    inputs: Vec<Fr>,
+pub struct Poseidon<F = Fr> {
+    inputs: Vec<F>,
 }
 
-impl Poseidon {
-    pub fn new(inputs: Vec<Fr>) -> Self {
+impl<F: PoseidonField> Poseidon<F> {
+    pub fn new(inputs: Vec<F>) -> Self {
         Self { inputs }
     }
 
-    /// Exp of S-box.
-    pub fn sigma(&self, item: Fr) -> Fr {
-        let item_2 = item * item;
-        let item_4 = item_2 * item_2;
-        item * item_4
+    /// Hash arithmetics.
+    pub fn poseidon_ex(&self, initial_state: F, n_outs: usize) -> Result<F, PoseidonError> {
+        Ok(self.poseidon_ex_multi(initial_state, n_outs)?[0])
     }
 
-    /// Adds round constants.
-    pub fn ark(&self, state: &Vec<Fr>, t: usize, c: &Vec<Fr>, r: usize) -> Self {
-        let mut new_state = Poseidon::new(vec![Fr::zero(); t]);
+    /// Hash arithmetics, returns the first n_outs elements of the final state.
+    pub fn poseidon_ex_multi(
+        &self,
+        initial_state: F,
+        n_outs: usize,
+    ) -> Result<Vec<F>, PoseidonError> {
+        let params = F::params(self.inputs.len() + 1)?;
+        self.poseidon_ex_multi_with_params(params, initial_state, n_outs)
+    }
 
-        for i in 0..t {
-            new_state[i] = state[i] + c[i + r];
+    /// Hash arithmetics with the given parameters, returns the first n_outs elements of the
+    /// final state.
+    pub fn poseidon_ex_multi_with_params(
+        &self,
+        params: &PoseidonParams<F>,
+        initial_state: F,
+        n_outs: usize,
+    ) -> Result<Vec<F>, PoseidonError> {
+        let t = self.inputs.len() + 1;
+        if params.t != t {
+            return Err(PoseidonError::UnsupportedArity(self.inputs.len()));
+        }
+        if !(1..=t).contains(&n_outs) {
+            return Err(PoseidonError::InvalidOutputs { n_outs, t });
         }
 
-        new_state
-    }
-
-    /// Computes MDS matrix for full rounds and do MixLayer operation.
-    pub fn mix(&self, state: &Vec<Fr>, t: usize, m: [[Fr; 3]; 3]) -> Self {
-        let mut new_state = Poseidon::new(vec![Fr::zero(); t]);
+        let mut state = Vec::with_capacity(t);
+        state.push(initial_state);
+        state.extend_from_slice(&self.inputs);
 
-        for i in 0..t {
-            let mut lc = [Fr::zero()];
+        let mut buffer = vec![F::ZERO; t];
+        permute_in_place(params, &mut state, &mut buffer);
+        state.truncate(n_outs);
 
-            for j in 0..t {
-                lc[0] += m[j][i] * state[j];
-            }
+        Ok(state)
+    }
+}
 
-            new_state[i] = lc[0];
-        }
+/// Applies the permutation in place to a state of width T with the field's parameters,
+/// without allocating. `state[0]` is the initial state and the rest are the inputs, as in
+/// PoseidonEx.
+pub fn permute<F: PoseidonField, const T: usize>(state: &mut [F; T]) -> Result<(), PoseidonError> {
+    permute_with_params(F::params(T)?, state)
+}
 
-        new_state
+/// Applies the permutation in place to a state of width T with the given parameters, without
+/// allocating.
+pub fn permute_with_params<F: PrimeField, const T: usize>(
+    params: &PoseidonParams<F>,
+    state: &mut [F; T],
+) -> Result<(), PoseidonError> {
+    if params.t != T {
+        return Err(PoseidonError::UnsupportedArity(T.saturating_sub(1)));
     }
 
-    /// Computes MDS matrix for partial rounds and do MixLayer operation.
-    pub fn mixs(&self, state: &Vec<Fr>, t: usize, s: &Vec<Fr>, r: usize) -> Self {
-        let mut new_state = Poseidon::new(vec![Fr::zero(); t]);
-
-        for i in 0..t {
-            new_state[0] += s[(t * 2 - 1) * r + i] * state[i];
-        }
+    let mut buffer = [F::ZERO; T];
+    permute_in_place(params, state, &mut buffer);
 
-        for i in 1..t {
-            new_state[i] = state[i] + state[0] * s[(t * 2 - 1) * r + t + i - 1];
-        }
+    Ok(())
+}
 
-        new_state
+/// Applies circomlib's optimized permutation in place. The buffer holds the products of the
+/// full mix layers and must have the same width as the state.
+pub(crate) fn permute_in_place<F: PrimeField>(
+    params: &PoseidonParams<F>,
+    state: &mut [F],
+    buffer: &mut [F],
+) {
+    let t = params.t;
+    let half = params.n_rounds_f / 2;
+    let n_rounds_p = params.n_rounds_p;
+    let c = &params.c;
+    assert!(state.len() == t && buffer.len() == t, "Invalid parameters!");
+
+    ark(state, &c[..t]);
+
+    for r in 0..half {
+        sigma_all(state);
+        ark(state, &c[(r + 1) * t..(r + 2) * t]);
+        let m = if r == half - 1 { &params.p } else { &params.m };
+        mix(state, buffer, m);
     }
 
-    /// Computes MDS matrix for last round and do MixLayer operation.
-    pub fn mixlast(&self, state: &Vec<Fr>, t: usize, m: [[Fr; 3]; 3], s: usize) -> Fr {
-        let mut new_state = vec![Fr::zero()];
-
-        for j in 0..t {
-            new_state[0] += m[j][s] * state[j];
-        }
-
-        new_state[0]
+    for r in 0..n_rounds_p {
+        state[0] = sigma(state[0]) + c[(half + 1) * t + r];
+        mixs(state, &params.s[(t * 2 - 1) * r..(t * 2 - 1) * (r + 1)]);
     }
 
-    /// Hash arithmetics.
-    pub fn poseidon_ex(&self, initial_state: Fr, n_outs: usize) -> Fr {
-        let n_rounds_p = [
-            56, 57, 56, 60, 60, 63, 64, 63, 60, 66, 60, 65, 70, 60, 64, 68,
-        ];
-        let t = self.inputs.len() + 1;
-        let n_rounds_f = 8;
-        let n_round_p = n_rounds_p[t - 2];
-        let c = poseidon_c();
-        let s = poseidon_s();
-        let m = poseidon_m();
-        let p = poseidon_p();
-
-        let mut component_ark = vec![vec![Fr::zero(); t]; n_rounds_f];
-        let mut component_sigma_f = vec![vec![vec![Fr::zero()]; t]; n_rounds_f];
-        let mut component_sigma_p = vec![Fr::zero(); n_round_p];
-        let mut component_mix = vec![vec![Fr::zero(); t]; n_rounds_f - 1];
-        let mut component_mix_s = vec![vec![Fr::zero(); t]; n_round_p];
-        let mut component_mix_last = vec![Fr::zero(); n_outs];
-
-        let mut state = vec![Fr::zero(); t];
-
-        for j in 0..t {
-            if j > 0 {
-                state[j] = self.inputs[j - 1];
-            } else {
-                state[j] = initial_state;
-            }
-        }
+    for r in 0..half - 1 {
+        let offset = (half + 1) * t + n_rounds_p + r * t;
+        sigma_all(state);
+        ark(state, &c[offset..offset + t]);
+        mix(state, buffer, &params.m);
+    }
 
-        component_ark[0] = self.ark(&state, t, &c, 0).inputs;
+    sigma_all(state);
+    mix(state, buffer, &params.m);
+}
 
-        for r in 0..n_rounds_f / 2 - 1 {
-            for j in 0..t {
-                if r == 0 {
-                    component_sigma_f[r][j][0] = self.sigma(component_ark[0][j]);
-                } else {
-                    component_sigma_f[r][j][0] = self.sigma(component_mix[r - 1][j]);
-                }
-            }
+/// Exp of S-box.
+fn sigma<F: PrimeField>(item: F) -> F {
+    let item_2 = item * item;
+    let item_4 = item_2 * item_2;
+    item * item_4
+}
 
-            let state: Vec<Fr> = component_sigma_f[r].iter().map(|item| item[0]).collect();
-            component_ark[r + 1] = self.ark(&state, t, &c, (r + 1) * t).inputs;
-            component_mix[r] = self.mix(&component_ark[r + 1], t, m).inputs;
-        }
+/// Applies the S-box to the whole state.
+fn sigma_all<F: PrimeField>(state: &mut [F]) {
+    state.iter_mut().for_each(|item| *item = sigma(*item));
+}
 
-        for j in 0..t {
-            component_sigma_f[n_rounds_f / 2 - 1][j][0] =
-                self.sigma(component_mix[n_rounds_f / 2 - 2][j]);
-        }
+/// Adds round constants.
+fn ark<F: PrimeField>(state: &mut [F], c: &[F]) {
+    for (item, c) in state.iter_mut().zip(c) {
+        *item += c;
+    }
+}
 
-        let state: Vec<Fr> = component_sigma_f[n_rounds_f / 2 - 1]
+/// Multiplies by the (transposed) MDS matrix for full rounds.
+fn mix<F: PrimeField>(state: &mut [F], buffer: &mut [F], m: &[Vec<F>]) {
+    for (i, out) in buffer.iter_mut().enumerate() {
+        *out = m
             .iter()
-            .map(|item| item[0])
-            .collect();
-        component_ark[n_rounds_f / 2] = self.ark(&state, t, &c, (n_rounds_f / 2) * t).inputs;
-        component_mix[n_rounds_f / 2 - 1] = self.mix(&component_ark[n_rounds_f / 2], t, p).inputs;
-
-        for r in 0..n_round_p {
-            if r == 0 {
-                component_sigma_p[r] = self.sigma(component_mix[n_rounds_f / 2 - 1][0]);
-            } else {
-                component_sigma_p[r] = self.sigma(component_mix_s[r - 1][0]);
-            }
-
-            let mut state: Vec<Fr> = vec![Fr::zero(); t];
-
-            for j in 0..t {
-                if j == 0 {
-                    state[j] = component_sigma_p[r] + c[(n_rounds_f / 2 + 1) * t + r];
-                } else {
-                    if r == 0 {
-                        state[j] = component_mix[n_rounds_f / 2 - 1][j];
-                    } else {
-                        state[j] = component_mix_s[r - 1][j];
-                    }
-                }
-            }
-
-            component_mix_s[r] = self.mixs(&state, t, &s, r).inputs;
-        }
-
-        for r in 0..n_rounds_f / 2 - 1 {
-            for j in 0..t {
-                if r == 0 {
-                    component_sigma_f[n_rounds_f / 2 + r][j][0] =
-                        self.sigma(component_mix_s[n_round_p - 1][j]);
-                } else {
-                    component_sigma_f[n_rounds_f / 2 + r][j][0] =
-                        self.sigma(component_mix[n_rounds_f / 2 + r - 1][j]);
-                }
-            }
-
-            let state: Vec<Fr> = component_sigma_f[n_rounds_f / 2 + r]
-                .iter()
-                .map(|item| item[0])
-                .collect();
-            component_ark[n_rounds_f / 2 + r + 1] = self
-                .ark(&state, t, &c, (n_rounds_f / 2 + 1) * t + n_round_p + r * t)
-                .inputs;
-            component_mix[n_rounds_f / 2 + r] = self
-                .mix(&component_ark[n_rounds_f / 2 + r + 1], t, m)
-                .inputs;
-        }
-
-        for j in 0..t {
-            component_sigma_f[n_rounds_f - 1][j][0] = self.sigma(component_mix[n_rounds_f - 2][j]);
-        }
+            .zip(state.iter())
+            .map(|(row, item)| row[i] * item)
+            .sum();
+    }
 
-        let state: Vec<Fr> = component_sigma_f[n_rounds_f - 1]
-            .iter()
-            .map(|item| item[0])
-            .collect();
-        component_mix_last[0] = self.mixlast(&state, t, m, 0);
+    state.copy_from_slice(buffer);
+}
 
-        component_mix_last[0]
+/// Multiplies by the sparse matrix of one partial round.
+fn mixs<F: PrimeField>(state: &mut [F], s: &[F]) {
+    let t = state.len();
+    let first: F = s[..t]
+        .iter()
+        .zip(state.iter())
+        .map(|(s, item)| *s * item)
+        .sum();
+
+    for i in 1..t {
+        state[i] += state[0] * s[t + i - 1];
     }
+
+    state[0] = first;
 }
 
-impl Index<usize> for Poseidon {
-    type Output = Fr;
+impl<F> Index<usize> for Poseidon<F> {
+    type Output = F;
 
     fn index(&self, idx: usize) -> &Self::Output {
         self.inputs.index(idx)
     }
 }
 
-impl IndexMut<usize> for Poseidon {
+impl<F> IndexMut<usize> for Poseidon<F> {
     fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
         self.inputs.index_mut(idx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use constants::{N_ROUNDS_F, N_ROUNDS_P};
+    use halo2::halo2curves::ff::Field;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_permute() {
+        // poseidon([1, 2]) from circomlibjs.
+        let mut state = [Fr::zero(), Fr::from(1), Fr::from(2)];
+        permute(&mut state).unwrap();
+        let expected = Fr::from_str_vartime(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+
+        assert_eq!(state[0], expected);
+    }
+
+    #[test]
+    fn test_permute_all_widths() {
+        // Checked against the textbook permutation over the unoptimized constants, not against
+        // `Poseidon`, which shares the optimized path with `permute`.
+        fn run<const T: usize>(rng: &mut StdRng) {
+            let mut state = [(); T].map(|_| Fr::random(&mut *rng));
+            let (c, m) = grain::bn254_parameters(T);
+            let expected = reference::permute(&state, c, m, N_ROUNDS_F, N_ROUNDS_P[T - 2]);
+
+            permute(&mut state).unwrap();
+            assert_eq!(state.to_vec(), expected);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        run::<2>(&mut rng);
+        run::<3>(&mut rng);
+        run::<4>(&mut rng);
+        run::<5>(&mut rng);
+        run::<6>(&mut rng);
+        run::<7>(&mut rng);
+        run::<8>(&mut rng);
+        run::<9>(&mut rng);
+        run::<10>(&mut rng);
+        run::<11>(&mut rng);
+        run::<12>(&mut rng);
+        run::<13>(&mut rng);
+        run::<14>(&mut rng);
+        run::<15>(&mut rng);
+        run::<16>(&mut rng);
+        run::<17>(&mut rng);
+    }
+
+    #[test]
+    fn test_permute_errors() {
+        assert_eq!(
+            permute(&mut [Fr::one()]),
+            Err(PoseidonError::UnsupportedArity(0))
+        );
+        assert_eq!(
+            permute(&mut [Fr::one(); 18]),
+            Err(PoseidonError::UnsupportedArity(17))
+        );
+
+        let params = Fr::params(3).unwrap();
+        assert_eq!(
+            permute_with_params(params, &mut [Fr::one(); 4]),
+            Err(PoseidonError::UnsupportedArity(3))
+        );
+    }
+}
@@ -1,44 +1,379 @@
 use super::*;
-use halo2::halo2curves::{bn256::Fr, ff::FromUniformBytes};
+use error::PoseidonError;
+use halo2::halo2curves::{bn256::Fr, ff::PrimeField};
+use num_bigint::BigUint;
+use params::PoseidonField;
 
-/// Returns congruent field element for the given hex string.
-pub fn hex_to_field(item: &str) -> Fr {
-    let item = &item[2..];
-    let mut bytes = hex::decode(item).expect("Invalid parameters!");
+/// Returns the field element for the given 0x-prefixed big-endian hex string, as circomlibjs
+/// prints them. Any case, odd numbers of digits and zero padding are accepted, up to two
+/// digits per byte of the field representation. Values not below the modulus are rejected.
+pub fn hex_to_field<F: PrimeField>(item: &str) -> Result<F, PoseidonError> {
+    let digits = hex_digits::<F>(item)?;
+    let bytes = if digits.len() % 2 == 1 {
+        hex::decode(format!("0{}", digits))
+    } else {
+        hex::decode(digits)
+    }
+    .map_err(|_| PoseidonError::InvalidHex(item.to_string()))?;
+
+    canonical(item, BigUint::from_bytes_be(&bytes))
+}
+
+/// Returns the field element for the given 0x-prefixed little-endian hex string, whole bytes
+/// only, with the digit limits of `hex_to_field`. Values not below the modulus are rejected.
+pub fn hex_le_to_field<F: PrimeField>(item: &str) -> Result<F, PoseidonError> {
+    let digits = hex_digits::<F>(item)?;
+    let bytes = hex::decode(digits).map_err(|_| PoseidonError::InvalidHex(item.to_string()))?;
+
+    canonical(item, BigUint::from_bytes_le(&bytes))
+}
+
+/// Returns the field element for the given decimal string, as in circom's input.json and as
+/// `field_to_dec` prints them. Signs, whitespace, leading zeros other than "0" itself and
+/// values not below the modulus are rejected.
+pub fn dec_to_field<F: PrimeField>(item: &str) -> Result<F, PoseidonError> {
+    if item.is_empty()
+        || !item.bytes().all(|byte| byte.is_ascii_digit())
+        || (item.len() > 1 && item.starts_with('0'))
+    {
+        return Err(PoseidonError::InvalidDecimal(item.to_string()));
+    }
+
+    // Without leading zeros, more digits than the modulus is a larger value.
+    if item.len() > modulus::<F>().to_str_radix(10).len() {
+        return Err(PoseidonError::NonCanonical(item.to_string()));
+    }
+
+    let value = BigUint::parse_bytes(item.as_bytes(), 10)
+        .ok_or_else(|| PoseidonError::InvalidDecimal(item.to_string()))?;
+    canonical(item, value)
+}
+
+/// Returns the field element for a 0x-prefixed big-endian hex string or a decimal string.
+pub fn str_to_field<F: PrimeField>(item: &str) -> Result<F, PoseidonError> {
+    if item.starts_with("0x") {
+        hex_to_field(item)
+    } else {
+        dec_to_field(item)
+    }
+}
+
+/// Returns the digits after the 0x prefix, there must be at least one and at most two per
+/// byte of the field representation.
+fn hex_digits<F: PrimeField>(item: &str) -> Result<&str, PoseidonError> {
+    let digits = item
+        .strip_prefix("0x")
+        .ok_or_else(|| PoseidonError::MissingPrefix(item.to_string()))?;
+
+    if digits.is_empty() || digits.len() > 2 * F::Repr::default().as_ref().len() {
+        return Err(PoseidonError::InvalidHex(item.to_string()));
+    }
+
+    Ok(digits)
+}
+
+/// Returns the field element for the parsed value if it is below the modulus.
+fn canonical<F: PrimeField>(item: &str, value: BigUint) -> Result<F, PoseidonError> {
+    if value >= modulus::<F>() {
+        return Err(PoseidonError::NonCanonical(item.to_string()));
+    }
+
+    Ok(reduce(&value))
+}
+
+/// Returns the field element for the given integer, values not below the modulus are rejected.
+pub fn biguint_to_field<F: PrimeField>(value: &BigUint) -> Result<F, PoseidonError> {
+    canonical(&value.to_string(), value.clone())
+}
+
+/// Returns the field element for the given 32 big-endian bytes, values not below the modulus
+/// are rejected.
+pub fn bytes_be_to_field<F: PrimeField>(bytes: &[u8; 32]) -> Result<F, PoseidonError> {
+    let item = format!("0x{}", hex::encode(bytes));
+    canonical(&item, BigUint::from_bytes_be(bytes))
+}
+
+/// Returns the field element for the given 32 little-endian bytes, values not below the
+/// modulus are rejected. Errors print the value as big-endian hex, as the other parsers.
+pub fn bytes_le_to_field<F: PrimeField>(bytes: &[u8; 32]) -> Result<F, PoseidonError> {
+    let mut be = *bytes;
+    be.reverse();
+    let item = format!("0x{}", hex::encode(be));
+    canonical(&item, BigUint::from_bytes_le(bytes))
+}
+
+/// Returns the integer value of the field element.
+pub fn field_to_biguint<F: PrimeField>(item: &F) -> BigUint {
+    // The byte order of the representation is not fixed by ff, it is told apart with one.
+    let repr = item.to_repr();
+    if F::ONE.to_repr().as_ref()[0] == 1 {
+        BigUint::from_bytes_le(repr.as_ref())
+    } else {
+        BigUint::from_bytes_be(repr.as_ref())
+    }
+}
+
+/// Returns the decimal string of the field element, as circomlibjs and circom's input.json.
+pub fn field_to_dec<F: PrimeField>(item: &F) -> String {
+    field_to_biguint(item).to_str_radix(10)
+}
+
+/// Returns the 0x-prefixed big-endian hex string of the field element, padded to 32 bytes.
+pub fn field_to_hex<F: PoseidonField>(item: &F) -> String {
+    format!("0x{}", hex::encode(field_to_bytes_be(item)))
+}
+
+/// Returns the 32 big-endian bytes of the field element, as `field_to_bytes_le`.
+pub fn field_to_bytes_be<F: PoseidonField>(item: &F) -> [u8; 32] {
+    let mut bytes = field_to_bytes_le(item);
     bytes.reverse();
-    let mut temp_bytes = [0; 64];
-    temp_bytes[..bytes.len()].copy_from_slice(&bytes[..]);
-    Fr::from_uniform_bytes(&temp_bytes)
+    bytes
 }
 
-/// Returns the round constants to be used in the permutation without the prefix.
-pub fn poseidon_c() -> Vec<Fr> {
-    let poseidon_c_raw = constants::poseidon_c_raw();
-    let poseidon_c: Vec<Fr> = poseidon_c_raw
+/// Returns the 32 little-endian bytes of the field element. Poseidon fields fit in 32 bytes,
+/// a `PoseidonField` implementation with a larger modulus panics here.
+pub fn field_to_bytes_le<F: PoseidonField>(item: &F) -> [u8; 32] {
+    let le = field_to_biguint(item).to_bytes_le();
+    assert!(le.len() <= 32, "Invalid parameters!");
+
+    let mut bytes = [0; 32];
+    bytes[..le.len()].copy_from_slice(&le);
+    bytes
+}
+
+/// Returns congruent field element for the given integer.
+pub(crate) fn reduce<F: PrimeField>(value: &BigUint) -> F {
+    let shift = F::from(u64::MAX) + F::ONE;
+    value
+        .to_u64_digits()
         .iter()
-        .map(|item| hex_to_field(item))
-        .collect();
+        .rev()
+        .fold(F::ZERO, |acc, digit| acc * shift + F::from(*digit))
+}
+
+/// Returns the modulus of the field as an integer.
+pub fn modulus<F: PrimeField>() -> BigUint {
+    let modulus = F::MODULUS.trim_start_matches("0x");
+    BigUint::parse_bytes(modulus.as_bytes(), 16).expect("Invalid parameters!")
+}
+
+/// Returns the round constants to be used in the permutation without the prefix.
+pub fn poseidon_c(t: usize) -> Vec<Fr> {
+    let poseidon_c_raw = constants::poseidon_c_raw(t);
+    let poseidon_c: Vec<Fr> = poseidon_c_raw.iter().map(|item| constant(item)).collect();
     poseidon_c
 }
 
 /// Returns the round constants to be used in the permutation without the prefix.
-pub fn poseidon_s() -> Vec<Fr> {
-    let poseidon_s_raw = constants::poseidon_s_raw();
-    let poseidon_s: Vec<Fr> = poseidon_s_raw
-        .iter()
-        .map(|item| hex_to_field(item))
-        .collect();
+pub fn poseidon_s(t: usize) -> Vec<Fr> {
+    let poseidon_s_raw = constants::poseidon_s_raw(t);
+    let poseidon_s: Vec<Fr> = poseidon_s_raw.iter().map(|item| constant(item)).collect();
     poseidon_s
 }
 
 /// Returns the mds matrix elements without prefix.
-pub fn poseidon_m() -> [[Fr; 3]; 3] {
-    let poseidon_m = constants::poseidon_m_raw();
-    poseidon_m.map(|row| row.map(|item| hex_to_field(item)))
+pub fn poseidon_m(t: usize) -> Vec<Vec<Fr>> {
+    let poseidon_m = constants::poseidon_m_raw(t);
+    poseidon_m
+        .iter()
+        .map(|row| row.iter().map(|item| constant(item)).collect())
+        .collect()
 }
 
 /// Returns the mds matrix elements without prefix.
-pub fn poseidon_p() -> [[Fr; 3]; 3] {
-    let poseidon_p = constants::poseidon_p_raw();
-    poseidon_p.map(|row| row.map(|item| hex_to_field(item)))
+pub fn poseidon_p(t: usize) -> Vec<Vec<Fr>> {
+    let poseidon_p = constants::poseidon_p_raw(t);
+    poseidon_p
+        .iter()
+        .map(|row| row.iter().map(|item| constant(item)).collect())
+        .collect()
+}
+
+/// Returns the field element for one of the shipped constants.
+fn constant(item: &str) -> Fr {
+    hex_to_field(item).expect("Invalid parameters!")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULUS: &str =
+        "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+    const MODULUS_HEX: &str = "0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
+
+    #[test]
+    fn test_hex_to_field() {
+        assert_eq!(hex_to_field::<Fr>("0x4d"), Ok(Fr::from(77)));
+        assert_eq!(hex_to_field::<Fr>("0x04d"), Ok(Fr::from(77)));
+        assert_eq!(hex_to_field::<Fr>("0x1"), Ok(Fr::from(1)));
+        assert_eq!(hex_to_field::<Fr>("0x0"), Ok(Fr::from(0)));
+        assert_eq!(hex_to_field::<Fr>("0x4D"), Ok(Fr::from(77)));
+
+        let error = hex_to_field::<Fr>("4d").unwrap_err();
+        assert_eq!(error, PoseidonError::MissingPrefix("4d".to_string()));
+
+        for item in ["0x", "0x4g", "0x 4d", "0x-4d", "0x+4d"] {
+            let error = hex_to_field::<Fr>(item).unwrap_err();
+            assert_eq!(error, PoseidonError::InvalidHex(item.to_string()));
+        }
+
+        // The modulus minus one is the largest canonical value.
+        let max = "0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000000";
+        assert_eq!(hex_to_field::<Fr>(max), Ok(-Fr::one()));
+
+        // The modulus and above are rejected instead of reduced.
+        let above = "0xf0644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
+        for item in [MODULUS_HEX, above] {
+            let error = hex_to_field::<Fr>(item).unwrap_err();
+            assert_eq!(error, PoseidonError::NonCanonical(item.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_hex_to_field_canonical() {
+        // Padding up to 32 bytes is accepted, as field_to_hex prints it.
+        let padded = field_to_hex(&Fr::from(77));
+        assert_eq!(padded.len(), 66);
+        assert_eq!(hex_to_field::<Fr>(&padded), Ok(Fr::from(77)));
+        assert_eq!(
+            hex_to_field::<Fr>(&padded.to_uppercase().replace("0X", "0x")),
+            Ok(Fr::from(77))
+        );
+        assert_eq!(hex_to_field::<Fr>("0x4dA"), Ok(Fr::from(0x4da)));
+
+        // Longer strings are rejected however small the value.
+        let long = format!("0x0{}", &padded[2..]);
+        let above = "0x0130644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
+        let zeros = format!("0x{}", "0".repeat(1000));
+        for item in [long.as_str(), above, zeros.as_str()] {
+            let error = hex_to_field::<Fr>(item).unwrap_err();
+            assert_eq!(error, PoseidonError::InvalidHex(item.to_string()));
+        }
+
+        let long = format!("0x{}00", &padded[2..]);
+        let error = hex_le_to_field::<Fr>(&long).unwrap_err();
+        assert_eq!(error, PoseidonError::InvalidHex(long));
+    }
+
+    #[test]
+    fn test_hex_le_to_field() {
+        assert_eq!(hex_le_to_field::<Fr>("0x4d00"), Ok(Fr::from(77)));
+        assert_eq!(hex_le_to_field::<Fr>("0x0001"), Ok(Fr::from(256)));
+
+        let error = hex_le_to_field::<Fr>("0x4d0").unwrap_err();
+        assert_eq!(error, PoseidonError::InvalidHex("0x4d0".to_string()));
+
+        let modulus = "0x010000f093f5e1439170b97948e833285d588181b64550b829a031e1724e6430";
+        let error = hex_le_to_field::<Fr>(modulus).unwrap_err();
+        assert_eq!(error, PoseidonError::NonCanonical(modulus.to_string()));
+    }
+
+    #[test]
+    fn test_dec_to_field() {
+        assert_eq!(dec_to_field::<Fr>("77"), Ok(Fr::from(77)));
+        assert_eq!(dec_to_field::<Fr>("0"), Ok(Fr::from(0)));
+
+        for item in [
+            "", "-1", "+1", " 1", "1_000", "0x4d", "1e3", "00", "077", "0077",
+        ] {
+            let error = dec_to_field::<Fr>(item).unwrap_err();
+            assert_eq!(error, PoseidonError::InvalidDecimal(item.to_string()));
+        }
+
+        // More digits than the modulus are rejected before parsing.
+        let long = "9".repeat(1000);
+        for item in [MODULUS, &format!("{}0", MODULUS), &long] {
+            let error = dec_to_field::<Fr>(item).unwrap_err();
+            assert_eq!(error, PoseidonError::NonCanonical(item.to_string()));
+        }
+
+        let max = (modulus::<Fr>() - 1u32).to_str_radix(10);
+        assert_eq!(dec_to_field::<Fr>(&max), Ok(-Fr::one()));
+        assert_eq!(
+            dec_to_field::<Fr>(&field_to_dec(&Fr::from(77))),
+            Ok(Fr::from(77))
+        );
+    }
+
+    #[test]
+    fn test_str_to_field() {
+        assert_eq!(str_to_field::<Fr>("0x4d"), str_to_field::<Fr>("77"));
+        assert!(str_to_field::<Fr>(MODULUS).is_err());
+        assert!(str_to_field::<Fr>(MODULUS_HEX).is_err());
+    }
+
+    #[test]
+    fn test_bytes_to_field() {
+        let mut be = [0; 32];
+        be[31] = 77;
+        let mut le = [0; 32];
+        le[0] = 77;
+
+        assert_eq!(bytes_be_to_field::<Fr>(&be), Ok(Fr::from(77)));
+        assert_eq!(bytes_le_to_field::<Fr>(&le), Ok(Fr::from(77)));
+
+        let modulus = hex::decode(&MODULUS_HEX[2..]).unwrap();
+        let mut be: [u8; 32] = modulus.try_into().unwrap();
+        assert!(bytes_be_to_field::<Fr>(&be).is_err());
+        be.reverse();
+        assert_eq!(
+            bytes_le_to_field::<Fr>(&be),
+            Err(PoseidonError::NonCanonical(MODULUS_HEX.to_string()))
+        );
+
+        let modulus = BigUint::parse_bytes(MODULUS.as_bytes(), 10).unwrap();
+        assert!(biguint_to_field::<Fr>(&modulus).is_err());
+        assert_eq!(biguint_to_field::<Fr>(&(modulus - 1u64)), Ok(-Fr::one()));
+    }
+
+    #[test]
+    fn test_field_to_str() {
+        // poseidon([1, 2]) from circomlibjs, its decimal and hex strings.
+        let dec = "7853200120776062878684798364095072458815029376092732009249414926327459813530";
+        let hex = "0x115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a";
+        let item = crate::hash(&[Fr::from(1), Fr::from(2)]).unwrap();
+
+        assert_eq!(field_to_dec(&item), dec);
+        assert_eq!(field_to_hex(&item), hex);
+        assert_eq!(dec_to_field::<Fr>(dec), Ok(item));
+        assert_eq!(hex_to_field::<Fr>(hex), Ok(item));
+
+        let be = field_to_bytes_be(&item);
+        let le = field_to_bytes_le(&item);
+        assert_eq!(le, Fr::to_bytes(&item));
+        assert_eq!(bytes_be_to_field::<Fr>(&be), Ok(item));
+        assert_eq!(bytes_le_to_field::<Fr>(&le), Ok(item));
+
+        let value = field_to_biguint(&item);
+        assert_eq!(value.to_str_radix(10), dec);
+        assert_eq!(biguint_to_field::<Fr>(&value), Ok(item));
+
+        // Values above half the modulus are not read as negative.
+        assert_eq!(
+            field_to_dec(&-Fr::one()),
+            "21888242871839275222246405745257275088548364400416034343698204186575808495616"
+        );
+        assert_eq!(field_to_dec(&Fr::zero()), "0");
+        assert_eq!(
+            field_to_hex(&Fr::from(77)),
+            "0x000000000000000000000000000000000000000000000000000000000000004d"
+        );
+    }
+
+    #[test]
+    fn test_field_to_str_fields() {
+        fn run<F: PoseidonField>() {
+            let item = -F::from(77);
+            assert_eq!(dec_to_field::<F>(&field_to_dec(&item)), Ok(item));
+            assert_eq!(hex_to_field::<F>(&field_to_hex(&item)), Ok(item));
+            assert_eq!(bytes_le_to_field::<F>(&field_to_bytes_le(&item)), Ok(item));
+            assert_eq!(field_to_biguint(&item), modulus::<F>() - 77u64);
+        }
+
+        run::<Fr>();
+        run::<halo2::halo2curves::pasta::Fp>();
+        #[cfg(feature = "bls12_381")]
+        run::<bls12_381::Scalar>();
+    }
 }
@@ -1,10 +1,48 @@
+//! Poseidon hash compatible with circomlib's Poseidon and PoseidonEx templates.
+//!
+//! `hash` and `Hasher` compute circomlib's outputs natively, `Sponge` absorbs and squeezes
+//! arbitrary lengths and `PoseidonChip` lays the permutation out in a halo2 circuit.
+//! BN254 uses circomlib's constants, other fields implementing `PoseidonField` use
+//! constants generated by the reference script (see `grain`).
+
 mod gadgets;
 
+pub use gadgets::{
+    batch::{hash_batch, hash_stream, HashStream},
+    checkpoint::CheckpointedMerkleTree,
+    chip::{PoseidonChip, PoseidonConfig},
+    constants::{N_ROUNDS_F, N_ROUNDS_P},
+    error::PoseidonError,
+    grain,
+    hasher::{hash, Hasher},
+    incremental::{IncrementalMerkleTree, ROOT_HISTORY_SIZE},
+    indexed::{IndexedInsertion, IndexedLeaf, IndexedMerkleTree, IndexedProof},
+    merkle::{MerkleProof, MerkleTree},
+    merkle_chip::{MerklePathChip, MerklePathConfig},
+    mmr::{MerkleMountainRange, MmrProof},
+    multiproof::{verify_batch, MerkleMultiproof},
+    nary::{NaryMerkleProof, NaryMerkleTree},
+    params::{PoseidonField, PoseidonParams},
+    reference,
+    smt::{SmtFunction, SmtProof, SmtUpdate, SparseMerkleTree},
+    sponge::Sponge,
+    storage::{FileStore, MemoryStore, NodeStore, PersistentMerkleTree},
+    templates::{permute, permute_with_params, Poseidon},
+    utils::{
+        biguint_to_field, bytes_be_to_field, bytes_le_to_field, dec_to_field, field_to_biguint,
+        field_to_bytes_be, field_to_bytes_le, field_to_dec, field_to_hex, hex_le_to_field,
+        hex_to_field, str_to_field,
+    },
+};
+
+/// The curves of the halo2 version the crate is built with, for the field types of the API.
+pub use halo2::halo2curves;
+/// BN254 scalar field, the field of circomlib's Poseidon.
+pub use halo2::halo2curves::bn256::Fr;
+
 #[cfg(test)]
 mod test {
-    use crate::gadgets::{templates::Poseidon, utils::hex_to_field};
-    use halo2::halo2curves::bn256::Fr;
-    use num_bigint::BigInt;
+    use crate::{field_to_dec, hash, hex_to_field, Fr, Hasher, Poseidon};
 
     #[test]
     fn test_poseidon() {
@@ -13,7 +51,7 @@ mod test {
             "0x0000000000000000000000000000000000000000000000000000000000000005",
             "0x000000000000000000000000000000000000000000000000000000000000004d",
         ]
-        .map(|item| hex_to_field(item))
+        .map(|item| hex_to_field::<Fr>(item).unwrap())
         .to_vec();
 
         // Output taken from https://zkrepl.dev
@@ -23,10 +61,107 @@ mod test {
         let poseidon = Poseidon::new(inputs);
         let initial_state = Fr::from(0);
         let n_outs = 1;
-        let output_fr = poseidon.poseidon_ex(initial_state, n_outs);
-        let output_bytes = Fr::to_bytes(&output_fr);
-        let output = BigInt::from_signed_bytes_le(&output_bytes).to_str_radix(10);
+        let output_fr = poseidon.poseidon_ex(initial_state, n_outs).unwrap();
+        let output = field_to_dec(&output_fr);
 
         assert_eq!(expected, output);
     }
+
+    #[test]
+    fn test_poseidon_all_widths() {
+        // Testing for 1..=16 inputs, all set to 1.
+        // Outputs taken from circomlibjs poseidon([1, ...]).
+        let expected = [
+            "18586133768512220936620570745912940619677854269274689475585506675881198879027",
+            "217234377348884654691879377518794323857294947151490278790710809376325639809",
+            "1243904711429961858774220647610724273798918457991486031567244100767259239747",
+            "3697322215802076228208066929658130683674438861307808350825760082336385039729",
+            "7336984428078952600237169304321914358474313324708186973411450536267727944123",
+            "19202028150024867662780481379838409946689630430681355990725140707642055614070",
+            "15587463278332567001059474568613449501521353873733009860467391356348271308984",
+            "10625958540850080708582130653289575059817043739407999759992126519343968888964",
+            "6586682917253118491448025671540200955685514868114810493222463743094482488391",
+            "21154062150014026117492338523992511721617667294911573944785740169656578526614",
+            "200076502165720972416075356324368328696787933591141519854940792926668635035",
+            "9147049232282027787779787872110122248895227518511153992880076767178851520446",
+            "21141212912010209782103706933957075341069918860157048242358829753699364396140",
+            "17263450536280360765024824545930384521348727874044273811465290575403010288093",
+            "12611434709264158640009478167693124562521108213274462558817914382701939903805",
+            "16247148725799187968432601021479716680539182929063252906051522933915398361998",
+        ];
+
+        for (i, expected) in expected.iter().enumerate() {
+            let inputs = vec![Fr::one(); i + 1];
+
+            let poseidon = Poseidon::new(inputs);
+            let initial_state = Fr::from(0);
+            let n_outs = 1;
+            let output_fr = poseidon.poseidon_ex(initial_state, n_outs).unwrap();
+            let output = field_to_dec(&output_fr);
+
+            assert_eq!(*expected, output);
+        }
+    }
+
+    #[test]
+    fn test_poseidon_multi_outs() {
+        // Testing for 2 inputs with 3 outputs.
+        let inputs = [Fr::from(1), Fr::from(2)].to_vec();
+
+        // Outputs taken from circomlibjs poseidon([1, 2], 0, 3).
+        let expected = [
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+            "7142104613055408817911962100316808866448378443474503659992478482890339429929",
+            "6549537674122432311777789598043107870002137484850126429160507761192163713804",
+        ];
+
+        let poseidon = Poseidon::new(inputs);
+        let initial_state = Fr::from(0);
+        let n_outs = 3;
+        let outputs_fr = poseidon.poseidon_ex_multi(initial_state, n_outs).unwrap();
+        let outputs: Vec<String> = outputs_fr.iter().map(field_to_dec).collect();
+
+        assert_eq!(expected.to_vec(), outputs);
+        assert_eq!(
+            poseidon.poseidon_ex(initial_state, n_outs),
+            Ok(outputs_fr[0])
+        );
+
+        // Testing for 4 inputs with a non-zero initial state and 5 outputs.
+        let inputs = [1, 2, 3, 4].map(Fr::from).to_vec();
+
+        // Outputs taken from circomlibjs poseidon([1, 2, 3, 4], 7, 5).
+        let expected = [
+            "1569211601569591254857354699102545060324851338714426496554851741114291465006",
+            "18118540014198121694143530756594271381184917128935124964411046137581165216435",
+            "7740803957134411608349441197992439443341994231333819596680714922772284514156",
+            "17521422583432461484246039657000100492314318511902762368385517284377490579222",
+            "7078425169742818540826118505946841392519176885421421067393042045177810937220",
+        ];
+
+        let poseidon = Poseidon::new(inputs);
+        let initial_state = Fr::from(7);
+        let n_outs = 5;
+        let outputs: Vec<String> = poseidon
+            .poseidon_ex_multi(initial_state, n_outs)
+            .unwrap()
+            .iter()
+            .map(field_to_dec)
+            .collect();
+
+        assert_eq!(expected.to_vec(), outputs);
+    }
+
+    #[test]
+    fn test_hash() {
+        // Output taken from circomlibjs poseidon([1, 2]).
+        let expected =
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530";
+
+        let inputs = [Fr::from(1), Fr::from(2)];
+        let output = hash(&inputs).unwrap();
+
+        assert_eq!(expected, field_to_dec(&output));
+        assert_eq!(Hasher::new(2).unwrap().hash(&inputs), Ok(output));
+    }
 }